@@ -0,0 +1,57 @@
+// lookups per second of threads all sampling the same resident tile, as on the top mip of a
+// texture seen from far away, shows how well hits scale with the number of threads
+use std::time::Instant;
+use tundra_textures::exr::math::Vec2;
+use tundra_textures::{utils, Conversion, Filter, SampleOptions, TextureCache, TxOptions};
+
+const LOOKUPS: usize = 2_000_000;
+
+pub fn main() {
+    let path = std::env::temp_dir().join(format!(
+        "tundra_textures_hot_tile_{}.png",
+        std::process::id()
+    ));
+    image::RgbImage::from_fn(256, 256, |x, y| image::Rgb([x as u8, y as u8, 0]))
+        .save(&path)
+        .unwrap();
+    let mut cache = TextureCache::empty();
+    cache.set_tx_options(TxOptions {
+        conversion: Conversion::OnDisk,
+        ..TxOptions::default()
+    });
+    let handle = cache
+        .add(
+            path.to_string_lossy().to_string(),
+            &utils::ColorSpace::Raw,
+            true,
+        )
+        .unwrap()
+        .remove(0);
+    let bilinear = SampleOptions::level(0, Filter::Bilinear);
+    let cores = std::thread::available_parallelism().map_or(1, |n| n.get());
+    println!("{cores} cores");
+
+    for threads in [1, 2, 4, 8, 16, 32] {
+        let start = Instant::now();
+        std::thread::scope(|scope| {
+            for _ in 0..threads {
+                scope.spawn(|| {
+                    let mut sum = 0.0;
+                    for i in 0..LOOKUPS / threads {
+                        // all within the first 64x64 tile
+                        let uv = Vec2((i % 60) as f32 / 256.0, (i % 50) as f32 / 256.0);
+                        sum += cache.texture(&handle, uv, &bilinear).x;
+                    }
+                    std::hint::black_box(sum);
+                });
+            }
+        });
+        let seconds = start.elapsed().as_secs_f64();
+        println!(
+            "{threads:>2} threads: {:>6.1} M lookups/s",
+            LOOKUPS as f64 / seconds / 1e6
+        );
+    }
+    let _ = std::fs::remove_file(&path);
+    let _ = std::fs::remove_file(path.with_extension("tx"));
+}
@@ -1,12 +1,21 @@
-use tundra_textures::utils;
+use tundra_textures::exr::math::Vec2;
+use tundra_textures::{utils, SampleOptions};
 
 pub fn main() {
     let texture_path1 = "/media/jakubvondra/Data/dev/muskox/tests/grid.tx";
-    let texture_path2 =
-        "/media/jakubvondra/Data/dev/tundra/repos/tundra-textures/examples/test.png";
+    //let texture_path2 = "/media/jakubvondra/Data/dev/tundra/repos/tundra-textures/examples/test.png";
     let texture_path2 =
         "/media/jakubvondra/Data/dev/tundra/repos/tundra-textures/examples/udimtest.<udim>.png";
     let mut cache = tundra_textures::TextureCache::empty();
-    cache.add(texture_path1.to_string(), &utils::ColorSpace::Srgb, false);
-    cache.add(texture_path2.to_string(), &utils::ColorSpace::Srgb, true);
+    let grid = cache
+        .add(texture_path1.to_string(), &utils::ColorSpace::Srgb, false)
+        .expect("could not add grid texture");
+    // a broken texture doesn't have to stop everything else
+    if let Err(e) = cache.add(texture_path2.to_string(), &utils::ColorSpace::Srgb, true) {
+        println!("skipping udims: {}", e);
+    }
+
+    // single call lookup, loads the tiles it needs
+    let rgba = cache.texture(&grid[0], Vec2(0.5, 0.5), &SampleOptions::default());
+    println!("grid center: {:?}", rgba);
 }
@@ -0,0 +1,104 @@
+use std::fmt;
+
+/// Everything that can go wrong while adding, converting or loading textures.
+///
+/// Bad input files and conversion settings come back as errors rather than panics, the renderer
+/// decides what to do: `TextureCache::add` and the `try_` lookups hand the error back,
+/// `TextureCache::texture` returns the cache's error colour instead so a broken tile doesn't take
+/// the whole render down.
+#[derive(Debug)]
+pub enum TextureError {
+    /// file could not be opened, read or its modification time queried
+    Io {
+        path: String,
+        source: std::io::Error,
+    },
+    /// file is not a readable exr, or one of its chunks is corrupt
+    Exr {
+        path: String,
+        source: exr::error::Error,
+    },
+    /// source image could not be decoded by maketx
+    Image {
+        path: String,
+        source: image::ImageError,
+    },
+    /// udim pattern is not a valid glob pattern
+    Pattern {
+        pattern: String,
+        source: glob::PatternError,
+    },
+    /// udim pattern which doesn't match any file
+    NoMatches { pattern: String },
+    /// path maketx can't derive the .tx path from
+    InvalidPath { path: String },
+    /// readable file the cache can't use as a texture
+    Unsupported { path: String, reason: String },
+    /// tx options the file can't be converted with
+    InvalidOptions { path: String, reason: String },
+}
+
+impl fmt::Display for TextureError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TextureError::Io { path, source } => write!(f, "could not read {:?}: {}", path, source),
+            TextureError::Exr { path, source } => {
+                write!(f, "could not read exr {:?}: {}", path, source)
+            }
+            TextureError::Image { path, source } => {
+                write!(f, "could not read image {:?}: {}", path, source)
+            }
+            TextureError::Pattern { pattern, source } => {
+                write!(f, "invalid file pattern {:?}: {}", pattern, source)
+            }
+            TextureError::NoMatches { pattern } => write!(f, "no files match {:?}", pattern),
+            TextureError::InvalidPath { path } => write!(f, "invalid texture path {:?}", path),
+            TextureError::Unsupported { path, reason } => {
+                write!(f, "unsupported texture {:?}: {}", path, reason)
+            }
+            TextureError::InvalidOptions { path, reason } => {
+                write!(f, "can't convert {:?}: {}", path, reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for TextureError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            TextureError::Io { source, .. } => Some(source),
+            TextureError::Exr { source, .. } => Some(source),
+            TextureError::Image { source, .. } => Some(source),
+            TextureError::Pattern { source, .. } => Some(source),
+            TextureError::NoMatches { .. }
+            | TextureError::InvalidPath { .. }
+            | TextureError::Unsupported { .. }
+            | TextureError::InvalidOptions { .. } => None,
+        }
+    }
+}
+
+impl TextureError {
+    pub(crate) fn io(path: &str, source: std::io::Error) -> Self {
+        TextureError::Io {
+            path: path.to_string(),
+            source,
+        }
+    }
+
+    pub(crate) fn exr(path: &str, source: exr::error::Error) -> Self {
+        TextureError::Exr {
+            path: path.to_string(),
+            source,
+        }
+    }
+
+    pub(crate) fn image(path: &str, source: image::ImageError) -> Self {
+        TextureError::Image {
+            path: path.to_string(),
+            source,
+        }
+    }
+}
+
+pub type Result<T> = std::result::Result<T, TextureError>;
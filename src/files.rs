@@ -0,0 +1,83 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+#[derive(Default)]
+struct PoolState {
+    // idle handles, least recently returned first
+    idle: VecDeque<(usize, BufReader<File>)>,
+    files_opened: u64,
+}
+
+/// Bounded pool of open texture files shared by all textures of a cache.
+///
+/// A tile load checks a handle out for the time of the read, so threads loading tiles of the same
+/// texture never share a seek position. Only idle handles are kept, once there are more than
+/// `max_open` of them the least recently used one gets closed.
+pub struct FileHandles {
+    max_open: AtomicUsize,
+    state: Mutex<PoolState>,
+}
+impl FileHandles {
+    pub fn new(max_open: usize) -> Self {
+        FileHandles {
+            max_open: AtomicUsize::new(max_open),
+            state: Mutex::new(PoolState::default()),
+        }
+    }
+
+    pub fn max_open(&self) -> usize {
+        self.max_open.load(Ordering::Relaxed)
+    }
+
+    pub fn set_max_open(&self, max_open: usize) {
+        self.max_open.store(max_open, Ordering::Relaxed);
+        let mut state = self.state.lock().unwrap();
+        while state.idle.len() > max_open {
+            state.idle.pop_front();
+        }
+    }
+
+    // number of idle handles kept open
+    pub fn files_open(&self) -> usize {
+        self.state.lock().unwrap().idle.len()
+    }
+
+    // how many times a file had to be opened
+    pub fn files_opened(&self) -> u64 {
+        self.state.lock().unwrap().files_opened
+    }
+
+    // idle handle of the texture file, or a freshly opened one
+    pub(crate) fn take(&self, texture_id: usize, path: &str) -> std::io::Result<BufReader<File>> {
+        {
+            let mut state = self.state.lock().unwrap();
+            if let Some(i) = state.idle.iter().rposition(|(id, _)| *id == texture_id) {
+                return Ok(state.idle.remove(i).unwrap().1);
+            }
+            state.files_opened += 1;
+        }
+        Ok(BufReader::new(File::open(path)?))
+    }
+
+    // returns the handle to the pool once the read is done
+    pub(crate) fn give_back(&self, texture_id: usize, file: BufReader<File>) {
+        let max_open = self.max_open();
+        let mut state = self.state.lock().unwrap();
+        state.idle.push_back((texture_id, file));
+        while state.idle.len() > max_open {
+            state.idle.pop_front();
+        }
+    }
+
+    // closes all idle handles of a texture that is going away
+    pub(crate) fn forget_texture(&self, texture_id: usize) {
+        self.state
+            .lock()
+            .unwrap()
+            .idle
+            .retain(|(id, _)| *id != texture_id);
+    }
+}
@@ -3,114 +3,861 @@
 // ./maketx --format exr /media/jakubvondra/Data/dev/muskox/tests/grid.exr
 // cargo run --release --example load
 
+use arc_swap::ArcSwapOption;
 pub use exr;
-use exr::math::Vec2;
-use glam;
+use exr::math::{RoundingMode, Vec2};
+use exr::meta::attribute::{LevelMode, TileDescription};
+use half::f16;
 use hashbrown::HashMap;
-use std::sync::{Arc, RwLock};
+use smallvec::SmallVec;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 
+pub mod error;
+pub mod files;
+pub mod memory;
+mod resample;
 mod txmake;
 pub mod utils;
 
+pub use error::TextureError;
+use files::FileHandles;
+use memory::{CacheStats, TileKey, TileTracker};
+pub use resample::MipFilter;
+pub use txmake::{Conversion, ResizePolicy, SampleFormat, TxOptions};
+
 pub fn add(left: usize, right: usize) -> usize {
     left + right
 }
 
+// idle file handles kept open by default, tile loads of textures with an idle handle skip the open
+const DEFAULT_MAX_OPEN_FILES: usize = 128;
+// magenta, hard to miss in a render
+const DEFAULT_ERROR_COLOR: glam::Vec4 = glam::Vec4::new(1.0, 0.0, 1.0, 1.0);
+
+// texture opened for the cache, added under the path it was loaded from
+type LoadedTexture = error::Result<Arc<Texture>>;
+
 pub struct TextureCache {
-    pub textures: HashMap<String, Arc<RwLock<Texture>>>,
+    pub textures: HashMap<String, Arc<Texture>>,
+    tracker: Arc<TileTracker>,
+    files: Arc<FileHandles>,
+    error_color: glam::Vec4,
+    missing_texture_fallback: bool,
+    // path and error message of every texture that could not be added or whose tiles could not be read
+    failed: Mutex<Vec<(String, String)>>,
+    tx_options: TxOptions,
 }
 impl TextureCache {
     pub fn empty() -> Self {
         TextureCache {
             textures: HashMap::new(),
+            tracker: Arc::new(TileTracker::unlimited()),
+            files: Arc::new(FileHandles::new(DEFAULT_MAX_OPEN_FILES)),
+            error_color: DEFAULT_ERROR_COLOR,
+            missing_texture_fallback: true,
+            failed: Mutex::new(Vec::new()),
+            tx_options: TxOptions::default(),
+        }
+    }
+
+    // cache which keeps the loaded tiles of all its textures under memory_budget bytes,
+    // the budget should comfortably fit the tiles of the largest lookup footprint
+    pub fn with_memory_budget(memory_budget: usize) -> Self {
+        TextureCache {
+            tracker: Arc::new(TileTracker::new(memory_budget)),
+            ..Self::empty()
         }
     }
-    pub fn add(&mut self, texture_path: String, source_cs: &utils::ColorSpace, force_maketx: bool) {
+
+    // upper bound of texture files kept open between tile loads, keep it under the process file limit
+    pub fn set_max_open_files(&self, max_open_files: usize) {
+        self.files.set_max_open(max_open_files);
+    }
+
+    // colour of placeholders for missing textures and of lookups whose tiles could not be read,
+    // placeholders keep the colour they were added with
+    pub fn set_error_color(&mut self, error_color: glam::Vec4) {
+        self.error_color = error_color;
+    }
+
+    pub fn error_color(&self) -> glam::Vec4 {
+        self.error_color
+    }
+
+    // when on (the default) textures that fail to load are replaced by a placeholder of the error colour,
+    // when off `add` returns the error instead
+    pub fn set_missing_texture_fallback(&mut self, missing_texture_fallback: bool) {
+        self.missing_texture_fallback = missing_texture_fallback;
+    }
+
+    // conversion settings of textures added through `add`
+    pub fn set_tx_options(&mut self, tx_options: TxOptions) {
+        self.tx_options = tx_options;
+    }
+
+    /// Paths and error messages of the textures which failed so far, e.g. to print a summary
+    /// at the end of the frame. A copy, so lookups on other threads can keep recording failures.
+    pub fn failed_textures(&self) -> Vec<(String, String)> {
+        self.failed.lock().unwrap().clone()
+    }
+
+    // records the failure unless the texture is already listed
+    fn record_failure(&self, path: &str, error: TextureError) {
+        let mut failed = self.failed.lock().unwrap();
+        if !failed.iter().any(|(failed_path, _)| failed_path == path) {
+            failed.push((path.to_string(), error.to_string()));
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            files_open: self.files.files_open(),
+            files_opened: self.files.files_opened(),
+            ..self.tracker.stats()
+        }
+    }
+
+    // adds texture or all textures matching the udim pattern, returns handles of the added textures,
+    // files other than .tx and tiled mip mapped exrs go through maketx first,
+    // textures that can't be converted or read get a placeholder registered under their source path,
+    // unless the fallback is off, then it returns the error of the first one
+    pub fn add(
+        &mut self,
+        texture_path: String,
+        source_cs: &utils::ColorSpace,
+        force_maketx: bool,
+    ) -> error::Result<Vec<TextureHandle>> {
+        let tx_options = self.tx_options.clone();
+        self.add_with_options(texture_path, source_cs, force_maketx, &tx_options)
+    }
+
+    // same as `add`, converting non .tx files with the given settings instead of the cache's ones
+    pub fn add_with_options(
+        &mut self,
+        texture_path: String,
+        source_cs: &utils::ColorSpace,
+        force_maketx: bool,
+        tx_options: &TxOptions,
+    ) -> error::Result<Vec<TextureHandle>> {
         let mut texture_paths: Vec<String> = Vec::new();
         if texture_path.contains("<") {
-            let pattern = utils::tags_to_pattern(texture_path);
-            let mut matching = utils::list_files_by_pattern(pattern);
-            texture_paths.append(&mut matching);
+            let pattern = utils::tags_to_pattern(texture_path.clone());
+            // a udim without any tile is as missing as a single file that doesn't exist
+            let matching = utils::list_files_by_pattern(pattern.clone()).and_then(|matching| {
+                match matching.is_empty() {
+                    true => Err(TextureError::NoMatches { pattern }),
+                    false => Ok(matching),
+                }
+            });
+            match matching {
+                Ok(mut matching) => texture_paths.append(&mut matching),
+                Err(e) if self.missing_texture_fallback => {
+                    return Ok(vec![self.add_placeholder(texture_path, e)]);
+                }
+                Err(e) => return Err(e),
+            }
         } else {
             texture_paths.push(texture_path)
         }
 
-        for p in texture_paths.iter() {
-            let tx_path = match p.ends_with(".tx") {
-                true => p.clone(),
-                false => txmake::maketx(p.clone(), &source_cs, force_maketx),
-            };
+        let loaded = self.load_files(&texture_paths, source_cs, force_maketx, tx_options);
+        let mut handles = Vec::with_capacity(texture_paths.len());
+        for (p, result) in texture_paths.into_iter().zip(loaded) {
+            match result {
+                Ok(texture) => handles.push(self.insert_texture(p, texture)),
+                Err(e) if self.missing_texture_fallback => handles.push(self.add_placeholder(p, e)),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(handles)
+    }
 
-            &self.textures.insert(
-                tx_path.clone(),
-                Arc::new(RwLock::new(Texture::new(tx_path))),
-            );
+    // converts and opens up to `max_concurrent_conversions` files at once, the threads shared
+    // out between them, results in the order of the paths
+    fn load_files(
+        &self,
+        paths: &[String],
+        source_cs: &utils::ColorSpace,
+        force_maketx: bool,
+        tx_options: &TxOptions,
+    ) -> Vec<LoadedTexture> {
+        let threads = tx_options.thread_count();
+        let workers = threads
+            .min(paths.len())
+            .min(tx_options.max_concurrent_conversions.max(1));
+        if workers <= 1 {
+            return paths
+                .iter()
+                .map(|p| self.load_file(p, source_cs, force_maketx, tx_options))
+                .collect();
+        }
+
+        let file_options = TxOptions {
+            threads: (threads / workers).max(1),
+            ..tx_options.clone()
+        };
+        let next = std::sync::atomic::AtomicUsize::new(0);
+        let mut loaded: Vec<(usize, LoadedTexture)> = std::thread::scope(|scope| {
+            let handles: Vec<_> = (0..workers)
+                .map(|_| {
+                    scope.spawn(|| {
+                        let mut loaded = Vec::new();
+                        loop {
+                            let i = next.fetch_add(1, Ordering::Relaxed);
+                            let Some(p) = paths.get(i) else {
+                                return loaded;
+                            };
+                            let result = self.load_file(p, source_cs, force_maketx, &file_options);
+                            loaded.push((i, result));
+                        }
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .flat_map(|handle| handle.join().unwrap())
+                .collect()
+        });
+        loaded.sort_by_key(|(i, _)| *i);
+        loaded.into_iter().map(|(_, result)| result).collect()
+    }
+
+    // converts the file if needed and opens it
+    fn load_file(
+        &self,
+        path: &str,
+        source_cs: &utils::ColorSpace,
+        force_maketx: bool,
+        tx_options: &TxOptions,
+    ) -> LoadedTexture {
+        let is_texture_file = utils::is_texture_file(path)?;
+        if !is_texture_file && tx_options.conversion == Conversion::InMemory {
+            let (levels, original_size) = txmake::maketx_in_memory(path, source_cs, tx_options)?;
+            let half_tiles = tx_options.sample_format.resolve(source_cs) == SampleFormat::F16;
+            let texture = Arc::new(Texture::from_levels(
+                path.to_string(),
+                &levels,
+                original_size,
+                tx_options,
+                half_tiles,
+                self.tracker.clone(),
+                self.files.clone(),
+            ));
+            return Ok(texture);
+        }
+
+        let tx_path = match is_texture_file {
+            true => path.to_string(),
+            false => txmake::maketx(
+                path.to_string(),
+                source_cs,
+                force_maketx,
+                tx_options,
+                |tx_path| self.reads_file(tx_path),
+            )?,
+        };
+
+        Ok(Arc::new(Texture::new(
+            tx_path,
+            self.tracker.clone(),
+            self.files.clone(),
+        )?))
+    }
+
+    // whether one of the textures reads its tiles from the file
+    fn reads_file(&self, path: &str) -> bool {
+        self.textures
+            .values()
+            .any(|texture| texture.metadata.is_some() && texture.path == path)
+    }
+
+    fn insert_texture(&mut self, key: String, texture: Arc<Texture>) -> TextureHandle {
+        // textures read from files get their tiles evicted, converted in memory ones keep them all
+        if texture.metadata.is_some() {
+            self.tracker
+                .register_texture(texture.id, Arc::downgrade(&texture));
+        }
+        self.textures.insert(key, texture.clone());
+        TextureHandle(texture)
+    }
+
+    // registers a texture of the error colour in place of the one that failed
+    fn add_placeholder(&mut self, path: String, error: TextureError) -> TextureHandle {
+        self.record_failure(&path, error);
+        let texture = Arc::new(Texture::constant(
+            path.clone(),
+            self.error_color,
+            self.tracker.clone(),
+            self.files.clone(),
+        ));
+        self.textures.insert(path, texture.clone());
+        TextureHandle(texture)
+    }
+
+    // handle of already added texture, path is the one it was added with,
+    // or for udims the path of the tile
+    pub fn handle(&self, path: &str) -> Option<TextureHandle> {
+        self.textures
+            .get(path)
+            .map(|texture| TextureHandle(texture.clone()))
+    }
+
+    /// Filtered lookup which loads all tiles the footprint needs, can be called from many threads at once.
+    /// Threads only wait for each other when they miss the same tile, which then gets decoded once.
+    /// Returns the error colour when a tile can't be read and lists the texture in `failed_textures`,
+    /// see `try_texture` to handle it yourself.
+    pub fn texture(
+        &self,
+        handle: &TextureHandle,
+        uv: Vec2<f32>,
+        options: &SampleOptions,
+    ) -> glam::Vec4 {
+        self.try_texture(handle, uv, options).unwrap_or_else(|e| {
+            self.record_failure(&handle.0.path, e);
+            self.error_color
+        })
+    }
+
+    // same lookup as `texture`, failing when one of the tiles can't be read
+    pub fn try_texture(
+        &self,
+        handle: &TextureHandle,
+        uv: Vec2<f32>,
+        options: &SampleOptions,
+    ) -> error::Result<glam::Vec4> {
+        let texture = &handle.0;
+        texture.sample_taps(&texture.lookup_taps(uv, options))
+    }
+}
+
+/// Cheap to clone reference to a texture of the cache, used for lookups through `TextureCache::texture`.
+#[derive(Clone)]
+pub struct TextureHandle(Arc<Texture>);
+impl TextureHandle {
+    pub fn texture(&self) -> &Arc<Texture> {
+        &self.0
+    }
+}
+
+/// Which texels a lookup reads from.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Footprint {
+    /// fixed mip level, texels combined with the filter
+    Level { mipmap_lvl: usize, filter: Filter },
+    /// bilinear in the two mip levels matching the uv derivatives
+    Trilinear {
+        duv_dx: Vec2<f32>,
+        duv_dy: Vec2<f32>,
+    },
+    /// probes along the ellipse given by the uv derivatives
+    Anisotropic {
+        duv_dx: Vec2<f32>,
+        duv_dy: Vec2<f32>,
+        max_anisotropy: f32,
+    },
+}
+
+/// Per lookup settings for `TextureCache::texture`.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct SampleOptions {
+    pub footprint: Footprint,
+    pub wrap: Vec2<Wrap>,
+}
+impl Default for SampleOptions {
+    fn default() -> Self {
+        SampleOptions {
+            footprint: Footprint::Level {
+                mipmap_lvl: 0,
+                filter: Filter::Bilinear,
+            },
+            wrap: Vec2(Wrap::Default, Wrap::Default),
+        }
+    }
+}
+impl SampleOptions {
+    pub fn level(mipmap_lvl: usize, filter: Filter) -> Self {
+        SampleOptions {
+            footprint: Footprint::Level { mipmap_lvl, filter },
+            ..Default::default()
+        }
+    }
+
+    pub fn trilinear(duv_dx: Vec2<f32>, duv_dy: Vec2<f32>) -> Self {
+        SampleOptions {
+            footprint: Footprint::Trilinear { duv_dx, duv_dy },
+            ..Default::default()
+        }
+    }
+
+    pub fn anisotropic(duv_dx: Vec2<f32>, duv_dy: Vec2<f32>, max_anisotropy: f32) -> Self {
+        SampleOptions {
+            footprint: Footprint::Anisotropic {
+                duv_dx,
+                duv_dy,
+                max_anisotropy,
+            },
+            ..Default::default()
+        }
+    }
+
+    pub fn with_wrap(self, wrap: Vec2<Wrap>) -> Self {
+        SampleOptions { wrap, ..self }
+    }
+}
+
+/// How texels around the lookup position get combined, chosen per lookup.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum Filter {
+    /// single texel the uv falls into
+    #[default]
+    Nearest,
+    /// blend of the four nearest texels, which can lie in up to four different tiles
+    Bilinear,
+}
+
+/// Tile of a mip map level, `tile_pos` and `tile_index` are what `Texture::tile_load` expects.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct TileRef {
+    pub mipmap_lvl: usize,
+    pub tile_pos: Vec2<usize>,
+    pub tile_index: usize,
+}
+
+/// How texel coordinates outside of the texture get mapped back onto it, set per axis.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum Wrap {
+    /// whatever the texture file specifies, clamp when it doesn't say
+    #[default]
+    Default,
+    /// repeats the texture, for tiling textures
+    Periodic,
+    /// repeats the edge texels
+    Clamp,
+    /// repeats the texture flipped every other period
+    Mirror,
+    /// everything outside of the texture is black
+    Black,
+}
+impl Wrap {
+    // parses the names used by the "wrapmodes" attribute of .tx files
+    pub fn from_name(name: &str) -> Option<Wrap> {
+        match name.trim() {
+            "default" => Some(Wrap::Default),
+            "periodic" => Some(Wrap::Periodic),
+            "clamp" => Some(Wrap::Clamp),
+            "mirror" => Some(Wrap::Mirror),
+            "black" => Some(Wrap::Black),
+            _ => None,
         }
     }
+
+    // maps texel coordinate onto 0..size, None when it lands in the black border
+    pub fn apply(self, coord: i64, size: usize) -> Option<usize> {
+        let size = size as i64;
+        let wrapped = match self {
+            Wrap::Periodic => coord.rem_euclid(size),
+            Wrap::Default | Wrap::Clamp => coord.clamp(0, size - 1),
+            Wrap::Mirror => {
+                let m = coord.rem_euclid(2 * size);
+                if m >= size {
+                    2 * size - 1 - m
+                } else {
+                    m
+                }
+            }
+            Wrap::Black => {
+                if coord < 0 || coord >= size {
+                    return None;
+                }
+                coord
+            }
+        };
+        Some(wrapped as usize)
+    }
+}
+
+// single texel read contributing to a filtered lookup
+struct Tap {
+    mipmap_lvl: usize,
+    texel: Vec2<usize>,
+    weight: f32,
 }
+type Taps = SmallVec<[Tap; 8]>;
 
 pub struct Texture {
     pub path: String,
     pub resolution: (usize, usize),
+    // size of the source image, differs from the resolution when maketx resized or padded it
+    pub original_resolution: (usize, usize),
+    // mip levels from the largest down, ripmapped textures keep their other rip levels after them,
+    // `level_index` finds those
     pub mipmaps: Vec<MipMap>,
+    levels: LevelTable,
+    // wrap modes used for lookups asking for Wrap::Default
+    pub wrap_modes: Vec2<Wrap>,
+    // tile size, level mode and level rounding of the file, all tile math follows it
+    pub tile_description: TileDescription,
+    // tiles of half float textures are kept as half in memory as well
+    half_tiles: bool,
+    // part of the levels covered by the source, less than one for padded textures
+    uv_scale: Vec2<f32>,
+    id: usize,
+    // header and the channels tiles are made of, None for textures whose tiles are all resident
+    // and never read from a file
+    metadata: Option<(exr::meta::MetaData, utils::ChannelLayout)>,
+    tracker: Arc<TileTracker>,
+    files: Arc<FileHandles>,
 }
 impl Texture {
-    pub fn new(file_path: String) -> Self {
-        let metadata = exr::meta::MetaData::read_from_file(file_path.clone(), false)
-            .expect(format!("could not read metadata from {:?}", file_path).as_str());
+    // reads the header and the chunk offsets once, tiles then get read straight from their offsets
+    pub fn new(
+        file_path: String,
+        tracker: Arc<TileTracker>,
+        files: Arc<FileHandles>,
+    ) -> error::Result<Self> {
+        let id = tracker.next_texture_id();
+        let mut file = files
+            .take(id, &file_path)
+            .map_err(|e| TextureError::io(&file_path, e))?;
+        let (metadata, chunk_offsets) = utils::read_meta_data_and_offsets(&mut file)
+            .map_err(|e| TextureError::exr(&file_path, e))?;
+        files.give_back(id, file);
         //println!("{:?}", metadata.headers[0].layer_size);
         //println!("{:?}",metadata.headers[0].channels);
 
         let resolution = metadata.headers[0].layer_size;
+        let wrap_modes = utils::header_wrap_modes(&metadata.headers[0]);
+        let (original_resolution, uv_scale) = utils::header_original_size(&metadata.headers[0]);
+        let half_tiles = metadata.headers[0]
+            .channels
+            .list
+            .iter()
+            .all(|channel| channel.sample_type == exr::meta::attribute::SampleType::F16);
+        let exr::meta::BlockDescription::Tiles(tile_description) = metadata.headers[0].blocks
+        else {
+            return Err(TextureError::Unsupported {
+                path: file_path,
+                reason: "scan line exr, only tiled files can be textures".to_string(),
+            });
+        };
+
+        // tiles get sampled as rgba, files without colour or grey channels can't give that
+        let channel_names: Vec<String> = metadata.headers[0]
+            .channels
+            .list
+            .iter()
+            .map(|channel| channel.name.to_string())
+            .collect();
+        let channel_layout = utils::ChannelLayout::from_names(
+            channel_names.iter().map(String::as_str),
+        )
+        .ok_or_else(|| TextureError::Unsupported {
+            path: file_path.clone(),
+            reason: "no R, G and B or Y channels".to_string(),
+        })?;
 
-        let mipmaps = exr::meta::mip_map_levels(exr::math::RoundingMode::Down, resolution)
-            .map(|m| MipMap::empty(m.1))
+        let layout = level_layout(tile_description, resolution);
+        let levels = LevelTable::new(&layout);
+        let mut mipmaps: Vec<MipMap> = layout
+            .iter()
+            .map(|(_, level_resolution)| {
+                MipMap::empty(*level_resolution, tile_description.tile_size)
+            })
             .collect();
+
+        // offset table lists the chunks in the same order the blocks are enumerated here
+        for (tile, offset) in metadata.headers[0]
+            .blocks_increasing_y_order()
+            .zip(chunk_offsets)
+        {
+            let level = levels.get(tile.location.level_index);
+            if let Some(mipmap) = level.and_then(|level| mipmaps.get_mut(level)) {
+                let tile_index = tile.location.tile_index;
+                let i = tile_index.y() * mipmap.tiles_n.x() + tile_index.x();
+                if let Some(chunk_offset) = mipmap.chunk_offsets.get_mut(i) {
+                    *chunk_offset = offset;
+                }
+            }
+        }
+
         println!("added to texture cache: {:?}", &file_path);
-        Texture {
+        Ok(Texture {
             path: file_path,
             resolution: (resolution.0, resolution.1),
-            mipmaps: mipmaps,
+            original_resolution: (original_resolution.0, original_resolution.1),
+            mipmaps,
+            levels,
+            wrap_modes,
+            tile_description,
+            half_tiles,
+            uv_scale,
+            id,
+            metadata: Some((metadata, channel_layout)),
+            tracker,
+            files,
+        })
+    }
+
+    // single texel texture returning the color everywhere, stands in for textures that failed to load
+    pub fn constant(
+        path: String,
+        color: glam::Vec4,
+        tracker: Arc<TileTracker>,
+        files: Arc<FileHandles>,
+    ) -> Self {
+        let mipmap = MipMap::empty(Vec2(1, 1), Vec2(1, 1));
+        mipmap.tiles[0].keep_resident(
+            Vec2(1, 1),
+            TilePixels::F32(color.to_array().to_vec()),
+            &tracker,
+        );
+        Texture {
+            path,
+            resolution: (1, 1),
+            original_resolution: (1, 1),
+            mipmaps: vec![mipmap],
+            levels: LevelTable::new(&[(Vec2(0, 0), Vec2(1, 1))]),
+            wrap_modes: Vec2(Wrap::Clamp, Wrap::Clamp),
+            tile_description: TileDescription {
+                tile_size: Vec2(1, 1),
+                level_mode: LevelMode::Singular,
+                rounding_mode: RoundingMode::Down,
+            },
+            half_tiles: false,
+            uv_scale: Vec2(1.0, 1.0),
+            id: tracker.next_texture_id(),
+            metadata: None,
+            tracker,
+            files,
+        }
+    }
+
+    // texture made of the converted levels, all its tiles stay resident for the lifetime of the texture
+    pub(crate) fn from_levels(
+        path: String,
+        levels: &[txmake::MipLevel],
+        original_resolution: Vec2<usize>,
+        tx_options: &TxOptions,
+        half_tiles: bool,
+        tracker: Arc<TileTracker>,
+        files: Arc<FileHandles>,
+    ) -> Self {
+        let tile_size = tx_options.tile_size;
+        let tile_description = TileDescription {
+            tile_size: Vec2(tile_size, tile_size),
+            level_mode: match tx_options.ripmaps {
+                true => LevelMode::RipMap,
+                false => LevelMode::MipMap,
+            },
+            rounding_mode: txmake::LEVEL_ROUNDING,
+        };
+        let resolution = levels[0].size;
+        let uv_scale = tx_options.resize.uv_scale(original_resolution, resolution);
+        let layout = level_layout(tile_description, resolution);
+        let level_table = LevelTable::new(&layout);
+        // the converted levels come in the order of the file, rip levels row by row
+        let rip_count_x =
+            exr::meta::compute_level_count(tile_description.rounding_mode, resolution.x());
+
+        // maketx names its channels R, G, B and A or Y and A
+        let channel_layout =
+            utils::ChannelLayout::from_names(levels[0].channels.iter().map(|(name, _)| *name))
+                .expect("converted levels have colour or grey channels");
+
+        let mipmaps = layout
+            .iter()
+            .map(|(level_index, _)| {
+                let level = match tile_description.level_mode {
+                    LevelMode::RipMap => &levels[level_index.y() * rip_count_x + level_index.x()],
+                    _ => &levels[level_index.x()],
+                };
+                let mipmap = MipMap::empty(level.size, tile_description.tile_size);
+                for tile_y in 0..mipmap.tiles_n.y() {
+                    for tile_x in 0..mipmap.tiles_n.x() {
+                        let origin = Vec2(tile_x * tile_size, tile_y * tile_size);
+                        let size = Vec2(
+                            tile_size.min(level.size.x() - origin.x()),
+                            tile_size.min(level.size.y() - origin.y()),
+                        );
+                        let channels: Vec<Vec<f32>> = level
+                            .channels
+                            .iter()
+                            .map(|(_, channel)| {
+                                (origin.y()..origin.y() + size.y())
+                                    .flat_map(|y| {
+                                        let row = y * level.size.x() + origin.x();
+                                        channel[row..row + size.x()].iter().copied()
+                                    })
+                                    .collect()
+                            })
+                            .collect();
+                        let tile_index = tile_y * mipmap.tiles_n.x() + tile_x;
+                        mipmap.tiles[tile_index].keep_resident(
+                            size,
+                            TilePixels::new(channel_layout.interleave(&channels), half_tiles),
+                            &tracker,
+                        );
+                    }
+                }
+                mipmap
+            })
+            .collect();
+
+        Texture {
+            path,
+            resolution: (resolution.x(), resolution.y()),
+            original_resolution: (original_resolution.x(), original_resolution.y()),
+            mipmaps,
+            levels: level_table,
+            wrap_modes: Vec2(Wrap::Clamp, Wrap::Clamp),
+            tile_description,
+            half_tiles,
+            uv_scale,
+            id: tracker.next_texture_id(),
+            metadata: None,
+            tracker,
+            files,
         }
     }
 
     // checks if tile for given sample is already loaded or needs loading, returns tile index and status - true loaded, false - needs loading
+    // lookups landing in a black border don't need any tile and report as loaded
     pub fn tile_loaded(&self, uv: Vec2<f32>, mipmap_lvl: usize) -> (bool, Vec2<usize>, usize) {
         // (is loaded ?, tile position, tile_index)
-        let mip_map_resolution = *&self.mipmaps[mipmap_lvl].resolution;
+        let Some(texel) = self.nearest_texel(uv, mipmap_lvl, self.wrap_modes) else {
+            return (true, Vec2(0, 0), 0);
+        };
+        let tile = self.texel_tile(mipmap_lvl, texel);
 
-        //println!("mm: {:?}, t_pos: {:?}, t_i: {:?}, n_ti: {:?}",mipmap_lvl,tile_pos, tile_index, n_tiles_x);
+        //println!("mm: {:?}, t_pos: {:?}, t_i: {:?}",mipmap_lvl,tile.tile_pos, tile.tile_index);
 
-        // pixel postion relative to the mip map
-        let mipmap_pixel_pos = Vec2(
-            (uv.x() * (mip_map_resolution.y()) as f32 - 1.0).round() as usize,
-            (uv.y() * (mip_map_resolution.x()) as f32 - 1.0).round() as usize,
+        (
+            self.mipmaps[mipmap_lvl].tiles[tile.tile_index].is_resident(),
+            tile.tile_pos,
+            tile.tile_index,
+        )
+    }
+
+    // tiles the lookup with given filter reads from which are not loaded yet
+    pub fn missing_tiles(
+        &self,
+        uv: Vec2<f32>,
+        mipmap_lvl: usize,
+        filter: Filter,
+        wrap: Vec2<Wrap>,
+    ) -> SmallVec<[TileRef; 8]> {
+        let mut taps = Taps::new();
+        self.push_taps(
+            &mut taps,
+            uv,
+            mipmap_lvl,
+            filter,
+            self.resolve_wrap(wrap),
+            1.0,
         );
+        self.taps_missing_tiles(&taps)
+    }
 
-        let tile_pos = Vec2(mipmap_pixel_pos.x() / 64, mipmap_pixel_pos.y() / 64);
-        let tile_index =
-            tile_pos.x() + tile_pos.y() * self.mipmaps[mipmap_lvl].tiles_n.x() as usize;
+    // tiles the trilinear lookup reads from which are not loaded yet, up to four in each of the two levels
+    pub fn missing_tiles_trilinear(
+        &self,
+        uv: Vec2<f32>,
+        duv_dx: Vec2<f32>,
+        duv_dy: Vec2<f32>,
+        wrap: Vec2<Wrap>,
+    ) -> SmallVec<[TileRef; 8]> {
+        self.taps_missing_tiles(&self.trilinear_taps(uv, duv_dx, duv_dy, wrap))
+    }
 
-        (
-            self.mipmaps[mipmap_lvl].tiles[tile_index].is_some(),
-            tile_pos,
+    // loads the tile unless it is resident already, concurrent calls for the same tile decode it only once
+    pub fn tile_load(
+        &self,
+        mipmap_lvl: usize,
+        tile_index: usize,
+        tile_pos: Vec2<usize>,
+    ) -> error::Result<Arc<Tile>> {
+        let slot = &self.mipmaps[mipmap_lvl].tiles[tile_index];
+        if let Some(tile) = slot.get() {
+            return Ok(tile);
+        }
+        let loading = slot.loading.lock().unwrap();
+        // another thread could have loaded the tile while we were waiting
+        if let Some(tile) = slot.get() {
+            return Ok(tile);
+        }
+
+        let mip_map_resolution = self.mipmaps[mipmap_lvl].resolution;
+        let tiles_size = self.mipmaps[mipmap_lvl].tiles_size;
+        let (metadata, channel_layout) = self
+            .metadata
+            .as_ref()
+            .expect("textures without a file keep all their tiles resident");
+        let chunk_offset = self.mipmaps[mipmap_lvl].chunk_offsets[tile_index];
+        let mut file = self
+            .files
+            .take(self.id, &self.path)
+            .map_err(|e| TextureError::io(&self.path, e))?;
+        // a handle left mid-chunk by a failed read isn't returned to the pool
+        let rgba_values = utils::load_tile_data(&mut file, metadata, channel_layout, chunk_offset)
+            .map_err(|e| TextureError::exr(&self.path, e))?;
+        self.files.give_back(self.id, file);
+        let tile_size = Vec2(
+            exr::meta::calculate_block_size(
+                mip_map_resolution.x(),
+                tiles_size.x(),
+                tile_pos.x() * tiles_size.x(),
+            )
+            .unwrap(),
+            exr::meta::calculate_block_size(
+                mip_map_resolution.y(),
+                tiles_size.y(),
+                tile_pos.y() * tiles_size.y(),
+            )
+            .unwrap(),
+        );
+        let tile = Arc::new(Tile {
+            tile_size,
+            pixels: TilePixels::new(rgba_values, self.half_tiles),
+            last_used: Arc::new(AtomicU64::new(0)),
+        });
+        slot.tile.store(Some(tile.clone()));
+        drop(loading);
+
+        let key = TileKey {
+            texture: self.id,
+            mipmap_lvl,
             tile_index,
-        )
+        };
+        self.tracker
+            .tile_loaded(key, tile.memory_size(), tile.last_used.clone());
+        Ok(tile)
     }
 
-    pub fn tile_load(&mut self, mipmap_lvl: usize, tile_index: usize, tile_pos: Vec2<usize>) {
-        let mip_map_resolution = *&self.mipmaps[mipmap_lvl].resolution;
+    // loads whichever of the tiles are missing
+    pub fn tiles_load(&self, tiles: &[TileRef]) -> error::Result<()> {
+        for tile in tiles {
+            self.tile_load(tile.mipmap_lvl, tile.tile_index, tile.tile_pos)?;
+        }
+        Ok(())
+    }
 
-        if self.mipmaps[mipmap_lvl].tiles[tile_index].is_none() {
-            let rgba_values = utils::load_tile_data(self.path.as_str(), mipmap_lvl, tile_pos);
-            let tile_size = Vec2(
-                exr::meta::calculate_block_size(mip_map_resolution.x(), 64, tile_pos.x()).unwrap(),
-                exr::meta::calculate_block_size(mip_map_resolution.y(), 64, tile_pos.y()).unwrap(),
-            );
-            self.mipmaps[mipmap_lvl].tiles[tile_index] = Some(Tile {
-                tile_size: tile_size, //TODO set actual tile size
-                pixels: rgba_values,
-            });
+    // drops the tile data unless it got reloaded since, it will get loaded again next time it is needed,
+    // lookups still holding the tile keep reading it until they are done
+    pub(crate) fn evict_tile(
+        &self,
+        mipmap_lvl: usize,
+        tile_index: usize,
+        last_used: &Arc<AtomicU64>,
+    ) {
+        let slot = &self.mipmaps[mipmap_lvl].tiles[tile_index].tile;
+        let current = slot.load();
+        if current
+            .as_ref()
+            .is_some_and(|tile| Arc::ptr_eq(&tile.last_used, last_used))
+        {
+            // a reload in between swapped in another tile, which then stays
+            slot.compare_and_swap(&current, None);
         }
     }
 
@@ -120,45 +867,566 @@ impl Texture {
         mipmap_lvl: usize,
         tile_pos: Vec2<usize>,
         tile_index: usize,
-    ) -> glam::Vec4 {
+    ) -> error::Result<glam::Vec4> {
+        if mipmap_lvl >= self.mipmaps.len() {
+            return Ok(glam::Vec4::ZERO);
+        }
+        match self.nearest_texel(uv, mipmap_lvl, self.wrap_modes) {
+            Some(texel) => {
+                let tile = self.tile_guard(mipmap_lvl, tile_index, tile_pos)?;
+                let tile = tile.as_ref().expect("guards hold loaded tiles");
+                Ok(self.fetch_from_tile(tile, mipmap_lvl, texel, tile_pos))
+            }
+            None => Ok(glam::Vec4::ZERO),
+        }
+    }
+
+    // samples the mip map level with given filter, loading the tiles reported by missing_tiles
+    pub fn sample_filtered(
+        &self,
+        uv: Vec2<f32>,
+        mipmap_lvl: usize,
+        filter: Filter,
+        wrap: Vec2<Wrap>,
+    ) -> error::Result<glam::Vec4> {
         if mipmap_lvl >= self.mipmaps.len() {
-            return glam::Vec4::ZERO;
+            return Ok(glam::Vec4::ZERO);
         }
-        let mip_map_resolution = *&self.mipmaps[mipmap_lvl].resolution;
+        let mut taps = Taps::new();
+        self.push_taps(
+            &mut taps,
+            uv,
+            mipmap_lvl,
+            filter,
+            self.resolve_wrap(wrap),
+            1.0,
+        );
+        self.sample_taps(&taps)
+    }
+
+    // bilinear lookup blended between the two mip levels closest to the footprint given by the uv derivatives,
+    // loading the tiles reported by missing_tiles_trilinear
+    pub fn sample_trilinear(
+        &self,
+        uv: Vec2<f32>,
+        duv_dx: Vec2<f32>,
+        duv_dy: Vec2<f32>,
+        wrap: Vec2<Wrap>,
+    ) -> error::Result<glam::Vec4> {
+        self.sample_taps(&self.trilinear_taps(uv, duv_dx, duv_dy, wrap))
+    }
+
+    // tiles the anisotropic lookup reads from which are not loaded yet
+    pub fn missing_tiles_anisotropic(
+        &self,
+        uv: Vec2<f32>,
+        duv_dx: Vec2<f32>,
+        duv_dy: Vec2<f32>,
+        max_anisotropy: f32,
+        wrap: Vec2<Wrap>,
+    ) -> SmallVec<[TileRef; 8]> {
+        self.taps_missing_tiles(&self.anisotropic_taps(uv, duv_dx, duv_dy, max_anisotropy, wrap))
+    }
+
+    // elliptical weighted average over the footprint given by the uv derivatives, approximated by trilinear probes
+    // spread along the major axis of the ellipse at the mip level matching its minor axis,
+    // loading the tiles reported by missing_tiles_anisotropic
+    pub fn sample_anisotropic(
+        &self,
+        uv: Vec2<f32>,
+        duv_dx: Vec2<f32>,
+        duv_dy: Vec2<f32>,
+        max_anisotropy: f32,
+        wrap: Vec2<Wrap>,
+    ) -> error::Result<glam::Vec4> {
+        self.sample_taps(&self.anisotropic_taps(uv, duv_dx, duv_dy, max_anisotropy, wrap))
+    }
+
+    // index in `mipmaps` of the level with the given x and y level index, on mip mapped textures
+    // only the diagonal exists
+    pub fn level_index(&self, level: Vec2<usize>) -> Option<usize> {
+        self.levels.get(level)
+    }
+
+    // fractional mip level at which the footprint given by the uv derivatives covers about one texel
+    pub fn mip_level(&self, duv_dx: Vec2<f32>, duv_dy: Vec2<f32>) -> f32 {
+        let footprint = self
+            .texel_footprint(duv_dx)
+            .max(self.texel_footprint(duv_dy));
+        self.footprint_mip_level(footprint)
+    }
+
+    // length of a uv space vector measured in texels of the top mip level
+    fn texel_footprint(&self, duv: Vec2<f32>) -> f32 {
+        let resolution = self.content_resolution(0);
+        glam::Vec2::new(duv.x() * resolution.x(), duv.y() * resolution.y()).length()
+    }
+
+    fn footprint_mip_level(&self, footprint: f32) -> f32 {
+        let max_lvl = (self.levels.mip_count - 1) as f32;
+        if footprint <= 1.0 || footprint.is_nan() {
+            0.0
+        } else {
+            footprint.log2().min(max_lvl)
+        }
+    }
+
+    fn trilinear_taps(
+        &self,
+        uv: Vec2<f32>,
+        duv_dx: Vec2<f32>,
+        duv_dy: Vec2<f32>,
+        wrap: Vec2<Wrap>,
+    ) -> Taps {
+        let mut taps = Taps::new();
+        if self.tile_description.level_mode == LevelMode::RipMap {
+            self.push_rip_taps(&mut taps, uv, duv_dx, duv_dy, self.resolve_wrap(wrap));
+            return taps;
+        }
+        let lvl = self.mip_level(duv_dx, duv_dy);
+        self.push_trilinear_taps(&mut taps, uv, lvl, self.resolve_wrap(wrap), 1.0);
+        taps
+    }
+
+    // bilinear lookups blended between the four rip levels closest to the footprint,
+    // x and y levels picked on their own so long thin footprints along an axis stay sharp across it
+    fn push_rip_taps(
+        &self,
+        taps: &mut Taps,
+        uv: Vec2<f32>,
+        duv_dx: Vec2<f32>,
+        duv_dy: Vec2<f32>,
+        wrap: Vec2<Wrap>,
+    ) {
+        let resolution = self.content_resolution(0);
+        let axis_level = |extent: f32, texels: f32, count: usize| {
+            let footprint = extent * texels;
+            if footprint <= 1.0 || footprint.is_nan() {
+                0.0
+            } else {
+                footprint.log2().min((count - 1) as f32)
+            }
+        };
+        let lvl_x = axis_level(
+            duv_dx.x().abs().max(duv_dy.x().abs()),
+            resolution.x(),
+            self.levels.count.x(),
+        );
+        let lvl_y = axis_level(
+            duv_dx.y().abs().max(duv_dy.y().abs()),
+            resolution.y(),
+            self.levels.count.y(),
+        );
+        let (x0, y0) = (lvl_x.floor() as usize, lvl_y.floor() as usize);
+        let (bx, by) = (lvl_x - x0 as f32, lvl_y - y0 as f32);
+
+        for (x, y, weight) in [
+            (x0, y0, (1.0 - bx) * (1.0 - by)),
+            (x0 + 1, y0, bx * (1.0 - by)),
+            (x0, y0 + 1, (1.0 - bx) * by),
+            (x0 + 1, y0 + 1, bx * by),
+        ] {
+            // levels that don't contribute are skipped, so their tiles don't need loading
+            if weight <= 0.0 {
+                continue;
+            }
+            if let Some(level) = self.levels.get(Vec2(x, y)) {
+                self.push_taps(taps, uv, level, Filter::Bilinear, wrap, weight);
+            }
+        }
+    }
+
+    fn push_trilinear_taps(
+        &self,
+        taps: &mut Taps,
+        uv: Vec2<f32>,
+        lvl: f32,
+        wrap: Vec2<Wrap>,
+        weight: f32,
+    ) {
+        let lvl_0 = lvl.floor() as usize;
+        let blend = lvl - lvl_0 as f32;
+
+        self.push_taps(
+            taps,
+            uv,
+            lvl_0,
+            Filter::Bilinear,
+            wrap,
+            weight * (1.0 - blend),
+        );
+        // skip the coarser level entirely when it doesn't contribute, so its tiles don't need loading
+        if blend > 0.0 && lvl_0 + 1 < self.levels.mip_count {
+            self.push_taps(taps, uv, lvl_0 + 1, Filter::Bilinear, wrap, weight * blend);
+        }
+    }
+
+    fn anisotropic_taps(
+        &self,
+        uv: Vec2<f32>,
+        duv_dx: Vec2<f32>,
+        duv_dy: Vec2<f32>,
+        max_anisotropy: f32,
+        wrap: Vec2<Wrap>,
+    ) -> Taps {
+        let wrap = self.resolve_wrap(wrap);
+        let (major_axis, major, minor) = self.footprint_ellipse(duv_dx, duv_dy);
+
+        // too eccentric ellipses get fattened along the minor axis, trading sharpness for a bounded probe count
+        let max_anisotropy = max_anisotropy.max(1.0);
+        let minor = minor.max(major / max_anisotropy);
+        let anisotropy = if minor > 0.0 { major / minor } else { 1.0 };
+        let n_probes = (anisotropy.ceil() as usize).max(1);
+        let lvl = self.footprint_mip_level(minor);
+
+        // gaussian falloff of the probes along the major axis
+        let probe_weights: SmallVec<[(f32, f32); 16]> = (0..n_probes)
+            .map(|i| {
+                let t = (i as f32 + 0.5) / n_probes as f32 - 0.5;
+                (t, (-2.0 * (2.0 * t).powi(2)).exp())
+            })
+            .collect();
+        let weight_sum: f32 = probe_weights.iter().map(|(_, w)| w).sum();
+
+        let mut taps = Taps::new();
+        for (t, w) in probe_weights {
+            let probe_uv = Vec2(uv.x() + major_axis.x() * t, uv.y() + major_axis.y() * t);
+            self.push_trilinear_taps(&mut taps, probe_uv, lvl, wrap, w / weight_sum);
+        }
+        taps
+    }
+
+    // axes of the ellipse the pixel maps onto in the top level, from the singular values of the
+    // jacobian of the uv derivatives in texel space like in heckbert's EWA, sheared derivatives
+    // included: the major axis as a uv space vector, the major and minor radius in texels
+    fn footprint_ellipse(&self, duv_dx: Vec2<f32>, duv_dy: Vec2<f32>) -> (Vec2<f32>, f32, f32) {
+        let resolution = self.content_resolution(0);
+        let to_texels = glam::Vec2::new(resolution.x(), resolution.y());
+        let jx = glam::Vec2::new(duv_dx.x(), duv_dx.y()) * to_texels;
+        let jy = glam::Vec2::new(duv_dy.x(), duv_dy.y()) * to_texels;
+
+        // eigen decomposition of J J^T, its eigenvalues are the squared singular values of J
+        let a = jx.x * jx.x + jy.x * jy.x;
+        let b = jx.x * jx.y + jy.x * jy.y;
+        let c = jx.y * jx.y + jy.y * jy.y;
+        let root = (0.25 * (a - c) * (a - c) + b * b).sqrt();
+        let major = (0.5 * (a + c) + root).max(0.0).sqrt();
+        if !major.is_finite() || major == 0.0 {
+            return (Vec2(0.0, 0.0), 0.0, 0.0);
+        }
+        // from the determinant, subtracting the eigenvalues loses the thin ones to rounding
+        let minor = (jx.x * jy.y - jx.y * jy.x).abs() / major;
+
+        // eigenvector of the larger eigenvalue, from whichever row of J J^T - major² is better conditioned
+        let lambda = major * major;
+        let direction = if a >= c {
+            glam::Vec2::new(lambda - c, b)
+        } else {
+            glam::Vec2::new(b, lambda - a)
+        };
+        let direction = direction.try_normalize().unwrap_or(glam::Vec2::X);
+        let axis = direction * major / to_texels;
+        (Vec2(axis.x, axis.y), major, minor)
+    }
+
+    fn lookup_taps(&self, uv: Vec2<f32>, options: &SampleOptions) -> Taps {
+        match options.footprint {
+            Footprint::Level { mipmap_lvl, filter } => {
+                let mut taps = Taps::new();
+                let mipmap_lvl = mipmap_lvl.min(self.levels.mip_count - 1);
+                let wrap = self.resolve_wrap(options.wrap);
+                self.push_taps(&mut taps, uv, mipmap_lvl, filter, wrap, 1.0);
+                taps
+            }
+            Footprint::Trilinear { duv_dx, duv_dy } => {
+                self.trilinear_taps(uv, duv_dx, duv_dy, options.wrap)
+            }
+            Footprint::Anisotropic {
+                duv_dx,
+                duv_dy,
+                max_anisotropy,
+            } => self.anisotropic_taps(uv, duv_dx, duv_dy, max_anisotropy, options.wrap),
+        }
+    }
+
+    // weighted sum of the taps, the tiles are held for the whole lookup so eviction can't pull them away midway
+    fn sample_taps(&self, taps: &[Tap]) -> error::Result<glam::Vec4> {
+        let mut tiles: SmallVec<[(TileRef, TileGuard); 8]> = SmallVec::new();
+        taps.iter().try_fold(glam::Vec4::ZERO, |sum, tap| {
+            let tile_ref = self.texel_tile(tap.mipmap_lvl, tap.texel);
+            let i = match tiles.iter().position(|(t, _)| *t == tile_ref) {
+                Some(i) => i,
+                None => {
+                    let tile = self.tile_guard(
+                        tile_ref.mipmap_lvl,
+                        tile_ref.tile_index,
+                        tile_ref.tile_pos,
+                    )?;
+                    tiles.push((tile_ref, tile));
+                    tiles.len() - 1
+                }
+            };
+            let tile = tiles[i].1.as_ref().expect("guards hold loaded tiles");
+            Ok(sum
+                + self.fetch_from_tile(tile, tap.mipmap_lvl, tap.texel, tile_ref.tile_pos)
+                    * tap.weight)
+        })
+    }
+
+    // resident tile without locking or touching its reference count, loads it when missing
+    fn tile_guard(
+        &self,
+        mipmap_lvl: usize,
+        tile_index: usize,
+        tile_pos: Vec2<usize>,
+    ) -> error::Result<TileGuard> {
+        let tile = self.mipmaps[mipmap_lvl].tiles[tile_index].tile.load();
+        if tile.is_some() {
+            return Ok(tile);
+        }
+        // holds on to the loaded tile itself, it could be evicted again before the slot is read
+        let tile = self.tile_load(mipmap_lvl, tile_index, tile_pos)?;
+        Ok(TileGuard::from_inner(Some(tile)))
+    }
+
+    fn taps_missing_tiles(&self, taps: &[Tap]) -> SmallVec<[TileRef; 8]> {
+        let mut missing: SmallVec<[TileRef; 8]> = SmallVec::new();
+        for tap in taps {
+            let tile = self.texel_tile(tap.mipmap_lvl, tap.texel);
+            if !self.mipmaps[tap.mipmap_lvl].tiles[tile.tile_index].is_resident()
+                && !missing.contains(&tile)
+            {
+                missing.push(tile);
+            }
+        }
+        missing
+    }
+
+    // replaces Wrap::Default with the texture's own wrap modes
+    fn resolve_wrap(&self, wrap: Vec2<Wrap>) -> Vec2<Wrap> {
+        let resolve = |wrap: Wrap, own: Wrap| match wrap {
+            Wrap::Default => own,
+            _ => wrap,
+        };
+        Vec2(
+            resolve(wrap.x(), self.wrap_modes.x()),
+            resolve(wrap.y(), self.wrap_modes.y()),
+        )
+    }
+
+    // texel of the mip map level the uv falls into, None when it lands in a black border
+    fn nearest_texel(
+        &self,
+        uv: Vec2<f32>,
+        mipmap_lvl: usize,
+        wrap: Vec2<Wrap>,
+    ) -> Option<Vec2<usize>> {
+        let resolution = self.content_resolution(mipmap_lvl);
+        self.wrap_texel(
+            mipmap_lvl,
+            (uv.x() * resolution.x()).floor() as i64,
+            (uv.y() * resolution.y()).floor() as i64,
+            wrap,
+        )
+    }
+
+    // texels of the mip map level covered by the source, the padding of padded textures left out,
+    // fractional on the levels where the source ends within a texel
+    fn content_resolution(&self, mipmap_lvl: usize) -> Vec2<f32> {
+        let resolution = self.mipmaps[mipmap_lvl].resolution;
+        Vec2(
+            resolution.x() as f32 * self.uv_scale.x(),
+            resolution.y() as f32 * self.uv_scale.y(),
+        )
+    }
 
-        // pixel postion relative to the mip map
-        let mipmap_pixel_pos = Vec2(
-            (uv.x() * (mip_map_resolution.y()) as f32 - 1.0).round() as usize,
-            (uv.y() * (mip_map_resolution.x()) as f32 - 1.0).round() as usize,
+    fn wrap_texel(
+        &self,
+        mipmap_lvl: usize,
+        x: i64,
+        y: i64,
+        wrap: Vec2<Wrap>,
+    ) -> Option<Vec2<usize>> {
+        // wraps around the source, never into the padding
+        let resolution = self.content_resolution(mipmap_lvl);
+        Some(Vec2(
+            wrap.x().apply(x, resolution.x().ceil() as usize)?,
+            wrap.y().apply(y, resolution.y().ceil() as usize)?,
+        ))
+    }
+
+    // adds the texels contributing to the lookup, with their weights scaled by weight,
+    // texels landing in a black border are left out so they contribute nothing
+    fn push_taps(
+        &self,
+        taps: &mut Taps,
+        uv: Vec2<f32>,
+        mipmap_lvl: usize,
+        filter: Filter,
+        wrap: Vec2<Wrap>,
+        weight: f32,
+    ) {
+        match filter {
+            Filter::Nearest => {
+                if let Some(texel) = self.nearest_texel(uv, mipmap_lvl, wrap) {
+                    taps.push(Tap {
+                        mipmap_lvl,
+                        texel,
+                        weight,
+                    });
+                }
+            }
+            Filter::Bilinear => {
+                let resolution = self.content_resolution(mipmap_lvl);
+                // texel centers sit at half integer positions
+                let x = uv.x() * resolution.x() - 0.5;
+                let y = uv.y() * resolution.y() - 0.5;
+                let (x0, y0) = (x.floor(), y.floor());
+                let (fx, fy) = (x - x0, y - y0);
+                let (x0, y0) = (x0 as i64, y0 as i64);
+
+                for (x, y, texel_weight) in [
+                    (x0, y0, (1.0 - fx) * (1.0 - fy)),
+                    (x0 + 1, y0, fx * (1.0 - fy)),
+                    (x0, y0 + 1, (1.0 - fx) * fy),
+                    (x0 + 1, y0 + 1, fx * fy),
+                ] {
+                    if let Some(texel) = self.wrap_texel(mipmap_lvl, x, y, wrap) {
+                        taps.push(Tap {
+                            mipmap_lvl,
+                            texel,
+                            weight: weight * texel_weight,
+                        });
+                    }
+                }
+            }
+        }
+    }
+
+    // tile of the mip map level holding the texel
+    fn texel_tile(&self, mipmap_lvl: usize, texel: Vec2<usize>) -> TileRef {
+        let mipmap = &self.mipmaps[mipmap_lvl];
+        let tile_pos = Vec2(
+            texel.x() / mipmap.tiles_size.x(),
+            texel.y() / mipmap.tiles_size.y(),
         );
+        TileRef {
+            mipmap_lvl,
+            tile_pos,
+            tile_index: tile_pos.x() + tile_pos.y() * mipmap.tiles_n.x(),
+        }
+    }
+
+    fn fetch_from_tile(
+        &self,
+        tile: &Tile,
+        mipmap_lvl: usize,
+        texel: Vec2<usize>,
+        tile_pos: Vec2<usize>,
+    ) -> glam::Vec4 {
+        let tiles_size = self.mipmaps[mipmap_lvl].tiles_size;
 
         // pixel postion relative to the tile
         let tile_pixel_pos = Vec2(
-            mipmap_pixel_pos.x() - tile_pos.x() * 64,
-            mipmap_pixel_pos.y() - tile_pos.y() * 64,
+            texel.x() - tile_pos.x() * tiles_size.x(),
+            texel.y() - tile_pos.y() * tiles_size.y(),
         );
 
-        let tile_pixel_index = tile_pixel_pos.x()
-            + tile_pixel_pos.y()
-                * &self.mipmaps[mipmap_lvl].tiles[tile_index]
-                    .as_ref()
-                    .unwrap()
-                    .tile_size
-                    .x();
-        //println!("uv: {:?}, tile index: {:?}, tile size: {:?}, tile_pos: {:?}, mipmap_pixel_pos: {:?}, tile_pixel_pos: {:?}, tile_pixel_index: {:?}",uv, tile_index,&self.mipmaps[mipmap_lvl].tiles[tile_index].as_ref().unwrap().tile_size,tile_pos,mipmap_pixel_pos,tile_pixel_pos,tile_pixel_index);
-
-        //glam::Vec3::new(uv.x()*1.0, uv.y()*1.0, tile_id as f32 / ((n_tiles_x*n_tiles_y) as f32) )
-        let pixel_values = &self.mipmaps[mipmap_lvl].tiles[tile_index]
-            .as_ref()
-            .expect("trying to read tile that is not loaded yet")
-            .pixels;
-
-        glam::Vec4::new(
-            pixel_values[tile_pixel_index * 4],
-            pixel_values[tile_pixel_index * 4 + 1],
-            pixel_values[tile_pixel_index * 4 + 2],
-            pixel_values[tile_pixel_index * 4 + 3],
-        )
+        // only written when the clock moved on, so threads sharing a hot tile don't keep
+        // invalidating each other's copy of it
+        let now = self.tracker.now();
+        if tile.last_used.load(Ordering::Relaxed) < now {
+            tile.last_used.store(now, Ordering::Relaxed);
+        }
+
+        let tile_pixel_index = tile_pixel_pos.x() + tile_pixel_pos.y() * tile.tile_size.x();
+        //println!("tile size: {:?}, tile_pos: {:?}, texel: {:?}, tile_pixel_pos: {:?}, tile_pixel_index: {:?}", tile.tile_size, tile_pos, texel, tile_pixel_pos, tile_pixel_index);
+
+        tile.pixels.rgba(tile_pixel_index)
+    }
+}
+
+// level indices and resolutions in the order the levels are kept in `Texture::mipmaps`,
+// the mip chain of a ripmap first so mip level lookups work on it as on any mip mapped texture
+fn level_layout(
+    tiles: TileDescription,
+    resolution: Vec2<usize>,
+) -> Vec<(Vec2<usize>, Vec2<usize>)> {
+    match tiles.level_mode {
+        LevelMode::Singular => vec![(Vec2(0, 0), resolution)],
+        LevelMode::MipMap => exr::meta::mip_map_levels(tiles.rounding_mode, resolution)
+            .map(|(level, level_resolution)| (Vec2(level, level), level_resolution))
+            .collect(),
+        LevelMode::RipMap => {
+            let count = Vec2(
+                exr::meta::compute_level_count(tiles.rounding_mode, resolution.x()),
+                exr::meta::compute_level_count(tiles.rounding_mode, resolution.y()),
+            );
+            let (mut chain, others): (Vec<_>, Vec<_>) =
+                exr::meta::rip_map_levels(tiles.rounding_mode, resolution)
+                    .partition(|(level, _)| on_mip_chain(*level, count));
+            chain.sort_by_key(|(level, _)| level.x().max(level.y()));
+            chain.into_iter().chain(others).collect()
+        }
+    }
+}
+
+// whether the rip level is one a mip map of the same resolution has, those scaled equally in x and y
+// and, once the shorter side is down to a single texel, those scaled further along the longer side
+fn on_mip_chain(level: Vec2<usize>, count: Vec2<usize>) -> bool {
+    level.x() == level.y()
+        || (level.x() > level.y() && level.y() + 1 == count.y())
+        || (level.y() > level.x() && level.x() + 1 == count.x())
+}
+
+// position in `Texture::mipmaps` of every x and y level index of the file
+struct LevelTable {
+    count: Vec2<usize>,
+    indices: Vec<Option<usize>>,
+    // leading levels on the mip chain, the ones mip level lookups pick from
+    mip_count: usize,
+}
+impl LevelTable {
+    fn new(layout: &[(Vec2<usize>, Vec2<usize>)]) -> Self {
+        let count = layout.iter().fold(Vec2(0, 0), |count, (level, _)| {
+            Vec2(count.x().max(level.x() + 1), count.y().max(level.y() + 1))
+        });
+        let mut indices = vec![None; count.area()];
+        for (i, (level, _)) in layout.iter().enumerate() {
+            indices[level.y() * count.x() + level.x()] = Some(i);
+        }
+        LevelTable {
+            count,
+            indices,
+            mip_count: layout
+                .iter()
+                .take_while(|(level, _)| on_mip_chain(*level, count))
+                .count(),
+        }
+    }
+
+    fn get(&self, level: Vec2<usize>) -> Option<usize> {
+        if level.x() >= self.count.x() || level.y() >= self.count.y() {
+            return None;
+        }
+        self.indices[level.y() * self.count.x() + level.x()]
+    }
+}
+
+impl Drop for Texture {
+    fn drop(&mut self) {
+        if self.metadata.is_none() {
+            let bytes = self
+                .mipmaps
+                .iter()
+                .flat_map(|mipmap| &mipmap.tiles)
+                .filter_map(|slot| slot.get())
+                .map(|tile| tile.memory_size())
+                .sum();
+            self.tracker.in_memory_removed(bytes);
+        }
+        self.tracker.forget_texture(self.id);
+        self.files.forget_texture(self.id);
     }
 }
 
@@ -166,12 +1434,12 @@ pub struct MipMap {
     pub resolution: Vec2<usize>,
     pub tiles_size: Vec2<usize>,
     pub tiles_n: Vec2<usize>,
-    pub tiles: Vec<Option<Tile>>,
+    pub tiles: Vec<TileSlot>,
+    // file offset of each tile's chunk
+    chunk_offsets: Vec<u64>,
 }
 impl MipMap {
-    pub fn empty(resolution: Vec2<usize>) -> Self {
-        let tile_size = Vec2(64, 64);
-
+    pub fn empty(resolution: Vec2<usize>, tile_size: Vec2<usize>) -> Self {
         let tiles_n = Vec2(
             exr::meta::compute_block_count(resolution.x(), tile_size.x()),
             exr::meta::compute_block_count(resolution.y(), tile_size.y()),
@@ -179,21 +1447,95 @@ impl MipMap {
 
         let n_tiles = tiles_n.x() * tiles_n.y();
 
-        let tiles = (0..n_tiles).map(|tile_i| None).collect();
+        let tiles = (0..n_tiles).map(|_| TileSlot::default()).collect();
 
         //println!("mimap {:?} - {:?} tiles", resolution, n_tiles);
 
         MipMap {
-            resolution: resolution,
+            resolution,
             tiles_size: tile_size,
-            tiles_n: tiles_n,
-            tiles: tiles,
+            tiles_n,
+            tiles,
+            chunk_offsets: vec![0; n_tiles],
         }
     }
 }
 
+/// Residency of a single tile. Hits read the slot without locking or writing anything shared,
+/// loading is serialized per tile so a tile missed by many threads at once gets decoded once.
+#[derive(Default)]
+pub struct TileSlot {
+    tile: ArcSwapOption<Tile>,
+    loading: Mutex<()>,
+}
+impl TileSlot {
+    pub fn get(&self) -> Option<Arc<Tile>> {
+        self.tile.load_full()
+    }
+
+    pub fn is_resident(&self) -> bool {
+        self.tile.load().is_some()
+    }
+
+    // fills the slot with a tile which is never handed to the tracker, so it never gets evicted
+    fn keep_resident(&self, tile_size: Vec2<usize>, pixels: TilePixels, tracker: &TileTracker) {
+        let tile = Tile {
+            tile_size,
+            pixels,
+            last_used: Arc::new(AtomicU64::new(0)),
+        };
+        tracker.in_memory_added(tile.memory_size());
+        self.tile.store(Some(Arc::new(tile)));
+    }
+}
+
+// tile held by a lookup, the slot can drop it meanwhile without freeing it under the lookup
+type TileGuard = arc_swap::Guard<Option<Arc<Tile>>>;
+
 pub struct Tile {
     tile_size: Vec2<usize>,
-    pixels: Vec<f32>,
+    pixels: TilePixels,
+    last_used: Arc<AtomicU64>,
+}
+impl Tile {
+    // bytes this tile keeps allocated, counted against the cache memory budget
+    pub fn memory_size(&self) -> usize {
+        std::mem::size_of::<Tile>() + self.pixels.memory_size()
+    }
+}
+
+// R,G,B,A,R,G,B,A... values of a tile
+enum TilePixels {
+    F16(Vec<f16>),
+    F32(Vec<f32>),
+}
+impl TilePixels {
+    fn new(rgba_values: Vec<f32>, half: bool) -> Self {
+        match half {
+            true => TilePixels::F16(rgba_values.into_iter().map(f16::from_f32).collect()),
+            false => TilePixels::F32(rgba_values),
+        }
+    }
+
+    fn rgba(&self, pixel_index: usize) -> glam::Vec4 {
+        let i = pixel_index * 4;
+        match self {
+            TilePixels::F16(values) => glam::Vec4::new(
+                values[i].to_f32(),
+                values[i + 1].to_f32(),
+                values[i + 2].to_f32(),
+                values[i + 3].to_f32(),
+            ),
+            TilePixels::F32(values) => {
+                glam::Vec4::new(values[i], values[i + 1], values[i + 2], values[i + 3])
+            }
+        }
+    }
+
+    fn memory_size(&self) -> usize {
+        match self {
+            TilePixels::F16(values) => values.capacity() * std::mem::size_of::<f16>(),
+            TilePixels::F32(values) => values.capacity() * std::mem::size_of::<f32>(),
+        }
+    }
 }
-impl Tile {}
@@ -0,0 +1,211 @@
+use hashbrown::HashMap;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+
+use crate::Texture;
+
+/// Identifies a single tile of a single mip map level of a texture registered in the cache.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct TileKey {
+    pub texture: usize,
+    pub mipmap_lvl: usize,
+    pub tile_index: usize,
+}
+
+/// Snapshot of the tile memory counters, useful for tuning the memory budget.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CacheStats {
+    pub memory_budget: usize,
+    pub bytes_resident: usize,
+    pub bytes_peak: usize,
+    pub tiles_resident: usize,
+    pub tiles_loaded: u64,
+    pub tiles_evicted: u64,
+    // tiles of textures held fully in memory, these are never evicted and not part of the budget
+    pub bytes_in_memory: usize,
+    // idle texture files kept open and how many times files had to be opened
+    pub files_open: usize,
+    pub files_opened: u64,
+}
+
+struct ResidentTile {
+    bytes: usize,
+    last_used: Arc<AtomicU64>,
+    // tick of the load, tells queue entries of this load from ones of an earlier load of the same tile
+    loaded_at: u64,
+    // tick the tile last entered the queue, sampled since then means it gets another round
+    queued_at: u64,
+}
+
+#[derive(Default)]
+struct TrackerState {
+    tiles: HashMap<TileKey, ResidentTile>,
+    // eviction candidates oldest first, with the tick of the load they belong to,
+    // entries of evicted or forgotten tiles are skipped when they come up
+    queue: VecDeque<(TileKey, u64)>,
+    textures: HashMap<usize, Weak<Texture>>,
+    bytes_resident: usize,
+    bytes_peak: usize,
+    tiles_loaded: u64,
+    tiles_evicted: u64,
+}
+
+/// Keeps track of every resident tile across all textures of a cache and evicts tiles which
+/// haven't been sampled for a while once the memory budget is exceeded.
+///
+/// Tiles store the tick of the clock at the time they were last sampled, the clock advances with
+/// every tile load, so sampling only costs one relaxed atomic store. Eviction is a second chance
+/// sweep over the tiles in load order: a tile sampled since it was queued goes to the back of the
+/// queue, any other one gets evicted, so a load costs amortised constant time however many tiles
+/// are resident.
+pub struct TileTracker {
+    memory_budget: usize,
+    clock: AtomicU64,
+    next_texture_id: AtomicUsize,
+    bytes_in_memory: AtomicUsize,
+    state: Mutex<TrackerState>,
+}
+impl TileTracker {
+    pub fn new(memory_budget: usize) -> Self {
+        TileTracker {
+            memory_budget,
+            clock: AtomicU64::new(0),
+            next_texture_id: AtomicUsize::new(0),
+            bytes_in_memory: AtomicUsize::new(0),
+            state: Mutex::new(TrackerState::default()),
+        }
+    }
+
+    pub fn unlimited() -> Self {
+        Self::new(usize::MAX)
+    }
+
+    pub fn memory_budget(&self) -> usize {
+        self.memory_budget
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let state = self.state.lock().unwrap();
+        CacheStats {
+            memory_budget: self.memory_budget,
+            bytes_resident: state.bytes_resident,
+            bytes_peak: state.bytes_peak,
+            tiles_resident: state.tiles.len(),
+            tiles_loaded: state.tiles_loaded,
+            tiles_evicted: state.tiles_evicted,
+            bytes_in_memory: self.bytes_in_memory.load(Ordering::Relaxed),
+            ..CacheStats::default()
+        }
+    }
+
+    // current tick of the usage clock, stored into tiles when they are sampled
+    pub(crate) fn now(&self) -> u64 {
+        self.clock.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn next_texture_id(&self) -> usize {
+        self.next_texture_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    // tiles of a texture held fully in memory, counted apart from the evictable ones
+    pub(crate) fn in_memory_added(&self, bytes: usize) {
+        self.bytes_in_memory.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub(crate) fn in_memory_removed(&self, bytes: usize) {
+        self.bytes_in_memory.fetch_sub(bytes, Ordering::Relaxed);
+    }
+
+    // lets the tracker evict tiles of this texture while another texture is loading
+    pub(crate) fn register_texture(&self, texture_id: usize, texture: Weak<Texture>) {
+        self.state
+            .lock()
+            .unwrap()
+            .textures
+            .insert(texture_id, texture);
+    }
+
+    // drops all bookkeeping of a texture that is going away
+    pub(crate) fn forget_texture(&self, texture_id: usize) {
+        let mut state = self.state.lock().unwrap();
+        state.textures.remove(&texture_id);
+        let forgotten_bytes: usize = state
+            .tiles
+            .iter()
+            .filter(|(key, _)| key.texture == texture_id)
+            .map(|(_, tile)| tile.bytes)
+            .sum();
+        state.tiles.retain(|key, _| key.texture != texture_id);
+        state.queue.retain(|(key, _)| key.texture != texture_id);
+        state.bytes_resident -= forgotten_bytes;
+    }
+
+    /// Records a freshly loaded tile and evicts tiles not sampled recently until the budget is met.
+    pub(crate) fn tile_loaded(&self, key: TileKey, bytes: usize, last_used: Arc<AtomicU64>) {
+        let tick = self.clock.fetch_add(1, Ordering::Relaxed) + 1;
+        last_used.store(tick, Ordering::Relaxed);
+
+        // textures upgraded during eviction, dropped only after the state lock is released
+        let mut touched_textures = Vec::new();
+
+        let mut state = self.state.lock().unwrap();
+        let resident = ResidentTile {
+            bytes,
+            last_used,
+            loaded_at: tick,
+            queued_at: tick,
+        };
+        if let Some(replaced) = state.tiles.insert(key, resident) {
+            // tile got reloaded before its eviction was recorded
+            state.bytes_resident -= replaced.bytes;
+        }
+        state.queue.push_back((key, tick));
+        state.bytes_resident += bytes;
+        state.bytes_peak = state.bytes_peak.max(state.bytes_resident);
+        state.tiles_loaded += 1;
+
+        let mut key_seen = false;
+        while state.bytes_resident > self.memory_budget {
+            let Some((victim, loaded_at)) = state.queue.pop_front() else {
+                break;
+            };
+            let Some(tile) = state.tiles.get_mut(&victim) else {
+                continue;
+            };
+            if tile.loaded_at != loaded_at {
+                continue;
+            }
+            // the tile just loaded is about to be sampled, it stays, the second time it comes up
+            // everything else has had its second chance already
+            if victim == key {
+                state.queue.push_back((victim, loaded_at));
+                if key_seen {
+                    break;
+                }
+                key_seen = true;
+                continue;
+            }
+            // sampled since it was queued, goes round once more
+            if tile.last_used.load(Ordering::Relaxed) > tile.queued_at {
+                tile.queued_at = tick;
+                state.queue.push_back((victim, loaded_at));
+                continue;
+            }
+
+            let tile = state.tiles.remove(&victim).unwrap();
+            if let Some(texture) = state
+                .textures
+                .get(&victim.texture)
+                .and_then(|t| t.upgrade())
+            {
+                texture.evict_tile(victim.mipmap_lvl, victim.tile_index, &tile.last_used);
+                touched_textures.push(texture);
+            }
+            state.bytes_resident -= tile.bytes;
+            state.tiles_evicted += 1;
+        }
+        drop(state);
+        drop(touched_textures);
+    }
+}
@@ -0,0 +1,199 @@
+use exr::math::Vec2;
+use std::borrow::Cow;
+
+/// Reconstruction filter used to resample mip levels.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MipFilter {
+    /// average of the covered texels, blurry but never rings
+    Box,
+    Triangle,
+    /// cubic with B = C = 1/3, a good compromise between sharpness and ringing
+    Mitchell,
+    /// sharpest, rings on high contrast edges
+    #[default]
+    Lanczos3,
+    /// sinc in a kaiser window, sharp with less ringing than lanczos
+    Kaiser,
+}
+impl MipFilter {
+    // radius of the filter in source texels when not scaling
+    fn support(self) -> f32 {
+        match self {
+            MipFilter::Box => 0.5,
+            MipFilter::Triangle => 1.0,
+            MipFilter::Mitchell => 2.0,
+            MipFilter::Lanczos3 | MipFilter::Kaiser => 3.0,
+        }
+    }
+
+    fn weight(self, x: f32) -> f32 {
+        let x = x.abs();
+        match self {
+            MipFilter::Box => {
+                if x <= 0.5 {
+                    1.0
+                } else {
+                    0.0
+                }
+            }
+            MipFilter::Triangle => (1.0 - x).max(0.0),
+            MipFilter::Mitchell => mitchell(x, 1.0 / 3.0, 1.0 / 3.0),
+            MipFilter::Lanczos3 => {
+                if x < 3.0 {
+                    sinc(x) * sinc(x / 3.0)
+                } else {
+                    0.0
+                }
+            }
+            MipFilter::Kaiser => {
+                if x < 3.0 {
+                    sinc(x) * kaiser_window(x / 3.0, 4.0)
+                } else {
+                    0.0
+                }
+            }
+        }
+    }
+}
+
+fn sinc(x: f32) -> f32 {
+    if x == 0.0 {
+        1.0
+    } else {
+        let x = x * std::f32::consts::PI;
+        x.sin() / x
+    }
+}
+
+fn mitchell(x: f32, b: f32, c: f32) -> f32 {
+    let x2 = x * x;
+    let x3 = x2 * x;
+    if x < 1.0 {
+        ((12.0 - 9.0 * b - 6.0 * c) * x3 + (-18.0 + 12.0 * b + 6.0 * c) * x2 + (6.0 - 2.0 * b))
+            / 6.0
+    } else if x < 2.0 {
+        ((-b - 6.0 * c) * x3
+            + (6.0 * b + 30.0 * c) * x2
+            + (-12.0 * b - 48.0 * c) * x
+            + (8.0 * b + 24.0 * c))
+            / 6.0
+    } else {
+        0.0
+    }
+}
+
+// t in -1..1 across the window
+fn kaiser_window(t: f32, alpha: f32) -> f32 {
+    bessel_i0(alpha * (1.0 - t * t).max(0.0).sqrt()) / bessel_i0(alpha)
+}
+
+// modified bessel function of the first kind, order zero, by its power series
+fn bessel_i0(x: f32) -> f32 {
+    let mut sum = 1.0;
+    let mut term = 1.0;
+    let half_x2 = x * x / 4.0;
+    for k in 1..20 {
+        term *= half_x2 / (k * k) as f32;
+        sum += term;
+    }
+    sum
+}
+
+// source texels and normalized weights contributing to each destination texel along one axis
+fn contributions(src: usize, dst: usize, filter: MipFilter) -> Vec<Vec<(usize, f32)>> {
+    // a single texel stands for the whole axis, any window would favour the centre over the edges
+    if dst == 1 {
+        return vec![(0..src).map(|j| (j, 1.0 / src as f32)).collect()];
+    }
+    let scale = src as f32 / dst as f32;
+    // widened when shrinking, so every source texel gets its share
+    let filter_scale = scale.max(1.0);
+    let support = filter.support() * filter_scale;
+
+    (0..dst)
+        .map(|i| {
+            let center = (i as f32 + 0.5) * scale;
+            let first = (center - support).floor() as i64;
+            let last = (center + support).ceil() as i64;
+            let mut taps: Vec<(usize, f32)> = (first..=last)
+                .map(|j| {
+                    let weight = filter.weight((j as f32 + 0.5 - center) / filter_scale);
+                    // edge texels repeat past the border
+                    (j.clamp(0, src as i64 - 1) as usize, weight)
+                })
+                .filter(|(_, weight)| *weight != 0.0)
+                .collect();
+            let total: f32 = taps.iter().map(|(_, weight)| weight).sum();
+            for (_, weight) in taps.iter_mut() {
+                *weight /= total;
+            }
+            taps
+        })
+        .collect()
+}
+
+/// Resizes interleaved samples with `channels` values per texel, one axis at a time,
+/// the rows of each pass spread over `threads` threads. Samples already at the size are
+/// handed back as they are, without a copy.
+pub(crate) fn resize(
+    samples: &[f32],
+    size: Vec2<usize>,
+    channels: usize,
+    new_size: Vec2<usize>,
+    filter: MipFilter,
+    threads: usize,
+) -> Cow<'_, [f32]> {
+    if size == new_size {
+        return Cow::Borrowed(samples);
+    }
+
+    let horizontal = contributions(size.x(), new_size.x(), filter);
+    let row_len = new_size.x() * channels;
+    let mut rows = vec![0.0; row_len * size.y()];
+    for_row_runs(&mut rows, row_len, threads, |first_y, chunk| {
+        for (y, row) in (first_y..).zip(chunk.chunks_mut(row_len)) {
+            for (x, taps) in horizontal.iter().enumerate() {
+                let out = &mut row[x * channels..(x + 1) * channels];
+                for (src_x, weight) in taps {
+                    let src = (y * size.x() + src_x) * channels;
+                    for (out, src) in out.iter_mut().zip(&samples[src..src + channels]) {
+                        *out += src * weight;
+                    }
+                }
+            }
+        }
+    });
+
+    let vertical = contributions(size.y(), new_size.y(), filter);
+    let mut resized = vec![0.0; row_len * new_size.y()];
+    for_row_runs(&mut resized, row_len, threads, |first_y, chunk| {
+        for (taps, out_row) in vertical[first_y..].iter().zip(chunk.chunks_mut(row_len)) {
+            for (src_y, weight) in taps {
+                let src_row = &rows[src_y * row_len..(src_y + 1) * row_len];
+                for (out, src) in out_row.iter_mut().zip(src_row) {
+                    *out += src * weight;
+                }
+            }
+        }
+    });
+    Cow::Owned(resized)
+}
+
+// splits the rows into one run per thread and calls f with the index of the first row of each run
+pub(crate) fn for_row_runs<F>(samples: &mut [f32], row_len: usize, threads: usize, f: F)
+where
+    F: Fn(usize, &mut [f32]) + Sync,
+{
+    let rows = samples.len() / row_len.max(1);
+    if threads <= 1 || rows <= 1 {
+        f(0, samples);
+        return;
+    }
+    let rows_per_thread = rows.div_ceil(threads);
+    std::thread::scope(|scope| {
+        for (i, chunk) in samples.chunks_mut(rows_per_thread * row_len).enumerate() {
+            let f = &f;
+            scope.spawn(move || f(i * rows_per_thread, chunk));
+        }
+    });
+}
@@ -4,115 +4,265 @@ extern crate smallvec;
 
 use exr::math::RoundingMode;
 use exr::prelude::*;
-use image::{imageops::FilterType, GenericImageView};
-use itertools::Itertools; // for accessing image dimensions
-use smallvec::smallvec;
-use std::fs::File;
+use half::f16;
+use smallvec::SmallVec;
+use std::borrow::Cow;
 use std::path::Path;
 
+use crate::error::TextureError;
+use crate::resample::{self, MipFilter};
 use crate::utils;
 
 // exr imports
 extern crate exr;
 
-pub fn maketx(filepath: String, source_cs: &utils::ColorSpace, force: bool) -> String {
-    println!("creating");
-    let filepath_tx = format!("{}{}", &filepath[0..filepath.rfind(".").unwrap()], ".tx");
-    println!("creating {:?}", &filepath_tx);
+/// Where converted textures end up.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Conversion {
+    /// .tx file written next to the source, tiles get loaded and evicted on demand
+    #[default]
+    OnDisk,
+    /// pyramid kept in memory with all tiles resident, nothing gets written
+    InMemory,
+}
 
-    if (Path::new(&filepath_tx).is_file()
-        && utils::is_file_newer(filepath_tx.clone(), filepath.clone())
-        && !force)
-    {
-        println!("tx already up to date, skipping {:?}", &filepath_tx);
-        return filepath_tx;
+/// Sample type of the channels maketx writes, also used for the tiles of in-memory conversions.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SampleFormat {
+    /// F16 for sRGB colour maps, F32 for raw data maps
+    #[default]
+    Auto,
+    /// half float, enough for colour at half the disk and cache memory
+    F16,
+    /// full float, for data like displacement which needs the precision
+    F32,
+}
+impl SampleFormat {
+    // resolves Auto by the colour space of the source
+    pub fn resolve(self, source_cs: &utils::ColorSpace) -> SampleFormat {
+        match (self, source_cs) {
+            (SampleFormat::Auto, utils::ColorSpace::Srgb) => SampleFormat::F16,
+            (SampleFormat::Auto, utils::ColorSpace::Raw) => SampleFormat::F32,
+            (format, _) => format,
+        }
     }
+}
 
-    let img = image::open(&Path::new(filepath.as_str())).expect("could not read iamge file");
+/// What maketx does with sources whose sides aren't powers of two.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ResizePolicy {
+    /// keeps the size, levels get rounded up and can end up uneven
+    #[default]
+    Keep,
+    /// stretches every side to the next power of two
+    RoundUp,
+    /// shrinks every side to the power of two below
+    RoundDown,
+    /// keeps the texels and fills up to the next power of two with copies of the edge texels,
+    /// lookups only see the part covered by the source
+    Pad,
+}
+impl ResizePolicy {
+    // names used by the resize attribute of the .tx files maketx writes
+    pub fn name(self) -> &'static str {
+        match self {
+            ResizePolicy::Keep => "keep",
+            ResizePolicy::RoundUp => "round_up",
+            ResizePolicy::RoundDown => "round_down",
+            ResizePolicy::Pad => "pad",
+        }
+    }
 
-    // Get image dimensions
-    let (width, height) = img.dimensions();
-    let full_size = Vec2(width as usize, height as usize);
-    let size_rounding = RoundingMode::Up;
+    pub fn from_name(name: &str) -> Option<ResizePolicy> {
+        match name.trim() {
+            "keep" => Some(ResizePolicy::Keep),
+            "round_up" => Some(ResizePolicy::RoundUp),
+            "round_down" => Some(ResizePolicy::RoundDown),
+            "pad" => Some(ResizePolicy::Pad),
+            _ => None,
+        }
+    }
 
-    let mip_levels_sizes = exr::meta::mip_map_levels(size_rounding, full_size).collect::<Vec<_>>();
+    // size of the top level for a source of the given size
+    fn level_size(self, size: Vec2<usize>) -> Vec2<usize> {
+        let round = |side: usize| match self {
+            ResizePolicy::Keep => side,
+            ResizePolicy::RoundUp | ResizePolicy::Pad => side.next_power_of_two(),
+            ResizePolicy::RoundDown => 1 << side.ilog2(),
+        };
+        Vec2(round(size.x()), round(size.y()))
+    }
 
-    let mut red_mip_levels: Vec<FlatSamples> = Vec::with_capacity(mip_levels_sizes.len());
-    let mut green_mip_levels: Vec<FlatSamples> = Vec::with_capacity(mip_levels_sizes.len());
-    let mut blue_mip_levels: Vec<FlatSamples> = Vec::with_capacity(mip_levels_sizes.len());
+    // part of the top level covered by the source, as a fraction of its size
+    pub(crate) fn uv_scale(self, original_size: Vec2<usize>, size: Vec2<usize>) -> Vec2<f32> {
+        match self {
+            ResizePolicy::Pad => Vec2(
+                original_size.x() as f32 / size.x() as f32,
+                original_size.y() as f32 / size.y() as f32,
+            ),
+            _ => Vec2(1.0, 1.0),
+        }
+    }
+}
 
-    for (_index, level_size) in mip_levels_sizes.iter() {
-        let img_resized = img.resize(
-            level_size.0 as u32,
-            level_size.1 as u32,
-            FilterType::Lanczos3,
-        );
-        // Convert the PNG into raw RGB8 data
-        let rgb_img = img_resized.to_rgb8();
-        let raw_data = rgb_img.into_raw(); // Raw RGB8 data as Vec<u8>
+/// Settings of the conversion into tiled, mip mapped .tx files.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TxOptions {
+    // width and height of the tiles written, 64 suits the lossless compression best
+    pub tile_size: usize,
+    pub conversion: Conversion,
+    pub sample_format: SampleFormat,
+    // directory the .tx files get written to instead of next to their sources,
+    // named by a hash of the source path so several sources with the same name don't collide
+    pub cache_dir: Option<String>,
+    pub mip_filter: MipFilter,
+    // resample every level from the one above instead of the full resolution image,
+    // cheaper on big sources but the filter error adds up down the pyramid
+    pub mip_from_previous: bool,
+    pub resize: ResizePolicy,
+    // writes every combination of x and y level, for lookups with long thin footprints,
+    // about four times the texels of the top level against a third more for the mip levels,
+    // so roughly three times the disk and memory of the mip chain
+    pub ripmaps: bool,
+    // threads resampling the levels of a texture, and converting the files of a udim at once,
+    // 0 uses one per core, `max_concurrent_conversions` bounds how many files that is
+    pub threads: usize,
+    // files of a udim converted at the same time, each holds a few float copies of its
+    // top level until it is done, so this bounds the memory a large udim takes to convert,
+    // the threads get shared out between them
+    pub max_concurrent_conversions: usize,
+}
+impl Default for TxOptions {
+    fn default() -> Self {
+        TxOptions {
+            tile_size: 64,
+            conversion: Conversion::default(),
+            sample_format: SampleFormat::default(),
+            cache_dir: None,
+            mip_filter: MipFilter::default(),
+            mip_from_previous: false,
+            resize: ResizePolicy::default(),
+            ripmaps: false,
+            threads: 0,
+            max_concurrent_conversions: 2,
+        }
+    }
+}
+impl TxOptions {
+    // resolves 0 threads into the number of cores
+    pub fn thread_count(&self) -> usize {
+        match self.threads {
+            0 => std::thread::available_parallelism().map_or(1, |n| n.get()),
+            threads => threads,
+        }
+    }
 
-        // Create a buffer that holds the image data in f32 format (for EXR)
-        let rgb_f32: Vec<f32> = raw_data
-            .iter()
-            .map(|&byte| byte as f32 / 255.0) // Convert from u8 to f32 in the range [0, 1]
-            .collect();
+    // settings exr or the resampling can't work with, checked before anything gets converted
+    fn validate(&self, filepath: &str) -> crate::error::Result<()> {
+        if self.tile_size == 0 {
+            return Err(TextureError::InvalidOptions {
+                path: filepath.to_string(),
+                reason: "tile size must be at least one texel".to_string(),
+            });
+        }
+        Ok(())
+    }
+}
 
-        let mut r: Vec<f32> = Vec::with_capacity(rgb_f32.len() / 3);
-        let mut g: Vec<f32> = Vec::with_capacity(rgb_f32.len() / 3);
-        let mut b: Vec<f32> = Vec::with_capacity(rgb_f32.len() / 3);
+// refuses to write over a .tx that `in_use` says is still read,
+// its readers find the tiles through the chunk offsets of the file they opened
+pub fn maketx(
+    filepath: String,
+    source_cs: &utils::ColorSpace,
+    force: bool,
+    options: &TxOptions,
+    in_use: impl Fn(&str) -> bool,
+) -> crate::error::Result<String> {
+    options.validate(&filepath)?;
+    println!("creating");
+    let filepath_tx = tx_path(&filepath, options)?;
+    println!("creating {:?}", &filepath_tx);
 
-        match source_cs {
-            utils::ColorSpace::Srgb => {
-                for rgb in rgb_f32.chunks(3) {
-                    r.push(srgb_to_linear(rgb[0]) / 10.0);
-                    g.push(srgb_to_linear(rgb[1]) / 10.0);
-                    b.push(srgb_to_linear(rgb[2]) / 10.0);
-                }
-            }
-            // Raw not sure why the values come out in range 0.0 - 10.0 but have to deal with it
-            _ => {
-                for rgb in rgb_f32.chunks(3) {
-                    r.push(rgb[0] / 10.0);
-                    g.push(rgb[1] / 10.0);
-                    b.push(rgb[2] / 10.0);
+    // timestamps don't survive copies and restores, the content and the settings decide instead
+    let fingerprint = format!(
+        "source:{:016x} {}",
+        utils::file_hash(&filepath)?,
+        settings_fingerprint(source_cs, options)
+    );
+    if !force && tx_fingerprint(&filepath_tx).as_deref() == Some(fingerprint.as_str()) {
+        println!("tx already up to date, skipping {:?}", &filepath_tx);
+        return Ok(filepath_tx);
+    }
+    if in_use(&filepath_tx) {
+        return Err(TextureError::Unsupported {
+            path: filepath_tx,
+            reason:
+                "still read by the cache, converting it again would change it under its readers"
+                    .to_string(),
+        });
+    }
+
+    let source = read_source(&filepath)?;
+    let original_size = source.size;
+    let levels = mip_levels(source, source_cs, options);
+    let full_size = levels[0].size;
+
+    // every channel gets all its levels, exr stores the channels sorted by name
+    let names: Vec<&str> = levels[0].channels.iter().map(|(name, _)| *name).collect();
+    let mut channel_levels: Vec<Vec<FlatSamples>> = vec![Vec::new(); names.len()];
+    let sample_format = options.sample_format.resolve(source_cs);
+    for level in levels {
+        for (channel_i, (_, samples)) in level.channels.into_iter().enumerate() {
+            channel_levels[channel_i].push(match sample_format {
+                SampleFormat::F16 => {
+                    FlatSamples::F16(samples.into_iter().map(f16::from_f32).collect())
                 }
-            }
+                _ => FlatSamples::F32(samples),
+            });
         }
-        red_mip_levels.push(FlatSamples::F32(r));
-        green_mip_levels.push(FlatSamples::F32(g));
-        blue_mip_levels.push(FlatSamples::F32(b));
     }
+    let mip_maps = AnyChannels::sort(
+        names
+            .into_iter()
+            .zip(channel_levels)
+            .map(|(name, level_data)| {
+                let levels = match options.ripmaps {
+                    true => Levels::Rip {
+                        level_data: RipMaps {
+                            map_data: level_data,
+                            level_count: rip_level_count(full_size),
+                        },
+                        rounding_mode: LEVEL_ROUNDING,
+                    },
+                    false => Levels::Mip {
+                        level_data,
+                        rounding_mode: LEVEL_ROUNDING,
+                    },
+                };
+                AnyChannel::new(name, levels)
+            })
+            .collect::<SmallVec<_>>(),
+    );
 
-    let rgb_mip_maps = AnyChannels::sort(smallvec![
-        AnyChannel::new(
-            "R",
-            Levels::Mip {
-                level_data: red_mip_levels,
-                rounding_mode: size_rounding
-            }
-        ),
-        AnyChannel::new(
-            "G",
-            Levels::Mip {
-                level_data: green_mip_levels,
-                rounding_mode: size_rounding
-            }
-        ),
-        AnyChannel::new(
-            "B",
-            Levels::Mip {
-                level_data: blue_mip_levels,
-                rounding_mode: size_rounding
-            }
-        ),
-    ]);
-
-    let layer1 = Layer::new(
-        full_size,
-        LayerAttributes::named("main"),
-        Encoding::FAST_LOSSLESS,
-        rgb_mip_maps,
+    let encoding = Encoding {
+        blocks: Blocks::Tiles(Vec2(options.tile_size, options.tile_size)),
+        ..Encoding::FAST_LOSSLESS
+    };
+    let mut layer_attributes = LayerAttributes::named("main");
+    layer_attributes.other.insert(
+        Text::from(FINGERPRINT_ATTRIBUTE),
+        AttributeValue::Text(Text::from(fingerprint.as_str())),
     );
+    // the texture needs both to find the source within padded levels
+    layer_attributes.other.insert(
+        Text::from(ORIGINAL_SIZE_ATTRIBUTE),
+        AttributeValue::IntVec2(Vec2(original_size.x() as i32, original_size.y() as i32)),
+    );
+    layer_attributes.other.insert(
+        Text::from(RESIZE_ATTRIBUTE),
+        AttributeValue::Text(Text::from(options.resize.name())),
+    );
+    let layer1 = Layer::new(full_size, layer_attributes, encoding, mip_maps);
 
     // define the visible area of the canvas
     let image_attributes = ImageAttributes::new(IntegerBounds::from_dimensions(full_size));
@@ -120,10 +270,348 @@ pub fn maketx(filepath: String, source_cs: &utils::ColorSpace, force: bool) -> S
     let image = Image::empty(image_attributes).with_layer(layer1);
 
     println!("writing image...");
-    image.write().to_file(&filepath_tx).unwrap();
+    // written aside and moved in place, so nobody sharing the cache reads a half written file,
+    // the random suffix keeps conversions of the same source in one process apart
+    let filepath_tmp = format!("{}.{:016x}.tmp", filepath_tx, rand::random::<u64>());
+    let written = image
+        .write()
+        .to_file(&filepath_tmp)
+        .map_err(|e| TextureError::exr(&filepath_tmp, e))
+        .and_then(|_| {
+            std::fs::rename(&filepath_tmp, &filepath_tx)
+                .map_err(|e| TextureError::io(&filepath_tx, e))
+        });
+    if let Err(e) = written {
+        let _ = std::fs::remove_file(&filepath_tmp);
+        return Err(e);
+    }
 
     println!("created file {:?}", &filepath_tx);
-    filepath_tx
+    Ok(filepath_tx)
+}
+
+// bump whenever the conversion changes its output, so existing .tx files get converted again
+const MAKETX_VERSION: u32 = 9;
+
+// header attribute holding the hash of the source and the settings the .tx was made from
+const FINGERPRINT_ATTRIBUTE: &str = "tundra:fingerprint";
+
+// header attributes with the size of the source and the resize policy it was converted with
+pub(crate) const ORIGINAL_SIZE_ATTRIBUTE: &str = "tundra:original_size";
+pub(crate) const RESIZE_ATTRIBUTE: &str = "tundra:resize";
+
+// every setting that changes the content of the .tx
+fn settings_fingerprint(source_cs: &utils::ColorSpace, options: &TxOptions) -> String {
+    format!(
+        "maketx:{} colorspace:{:?} tile:{} samples:{:?} filter:{:?} from_previous:{} resize:{} ripmaps:{}",
+        MAKETX_VERSION,
+        source_cs,
+        options.tile_size,
+        options.sample_format.resolve(source_cs),
+        options.mip_filter,
+        options.mip_from_previous,
+        options.resize.name(),
+        options.ripmaps
+    )
+}
+
+// fingerprint stored in an existing .tx, None when there is no readable file or it has none
+fn tx_fingerprint(filepath_tx: &str) -> Option<String> {
+    let metadata = exr::meta::MetaData::read_from_file(filepath_tx, false).ok()?;
+    match metadata.headers[0]
+        .own_attributes
+        .other
+        .get(&Text::from(FINGERPRINT_ATTRIBUTE))
+    {
+        Some(AttributeValue::Text(fingerprint)) => Some(fingerprint.to_string()),
+        _ => None,
+    }
+}
+
+// where maketx writes the .tx of the source, next to it or into the cache directory,
+// the same for any settings, the fingerprint in the header tells whether it is up to date
+fn tx_path(filepath: &str, options: &TxOptions) -> crate::error::Result<String> {
+    let Some(cache_dir) = &options.cache_dir else {
+        let Some(extension_start) = filepath.rfind(".") else {
+            return Err(TextureError::InvalidPath {
+                path: filepath.to_string(),
+            });
+        };
+        return Ok(format!("{}{}", &filepath[0..extension_start], ".tx"));
+    };
+
+    let Some(stem) = Path::new(filepath).file_stem() else {
+        return Err(TextureError::InvalidPath {
+            path: filepath.to_string(),
+        });
+    };
+    // same source reached through a different relative path still maps onto the same file
+    let source = std::fs::canonicalize(filepath).map_err(|e| TextureError::io(filepath, e))?;
+    let hash = utils::fnv1a_64(source.to_string_lossy().as_bytes());
+    std::fs::create_dir_all(cache_dir).map_err(|e| TextureError::io(cache_dir, e))?;
+    let filepath_tx =
+        Path::new(cache_dir).join(format!("{}_{:016x}.tx", stem.to_string_lossy(), hash));
+    Ok(filepath_tx.to_string_lossy().to_string())
+}
+
+// decodes the image and builds the pyramid without writing anything, along with the size of the source
+pub(crate) fn maketx_in_memory(
+    filepath: &str,
+    source_cs: &utils::ColorSpace,
+    options: &TxOptions,
+) -> crate::error::Result<(Vec<MipLevel>, Vec2<usize>)> {
+    options.validate(filepath)?;
+    let source = read_source(filepath)?;
+    let original_size = source.size;
+    Ok((mip_levels(source, source_cs, options), original_size))
+}
+
+// level sizes of the converted textures, rounding up like the other tools making .tx files do
+pub(crate) const LEVEL_ROUNDING: RoundingMode = RoundingMode::Up;
+
+// number of rip levels along x and y of a top level of the given size
+fn rip_level_count(size: Vec2<usize>) -> Vec2<usize> {
+    Vec2(
+        exr::meta::compute_level_count(LEVEL_ROUNDING, size.x()),
+        exr::meta::compute_level_count(LEVEL_ROUNDING, size.y()),
+    )
+}
+
+// single level of the converted pyramid, channels named like in the exr: Y, Y A, R G B or R G B A,
+// the levels come in the order exr stores them
+pub(crate) struct MipLevel {
+    pub size: Vec2<usize>,
+    pub channels: Vec<(&'static str, Vec<f32>)>,
+}
+
+// decoded source, `names.len()` interleaved float values per texel
+pub(crate) struct SourceImage {
+    pub size: Vec2<usize>,
+    // channels of the converted texture, Y, Y A, R G B or R G B A following the source
+    pub names: &'static [&'static str],
+    pub samples: Vec<f32>,
+}
+
+// exrs are read with exr, which takes any channels and scan line or single level files,
+// everything else with image
+fn read_source(filepath: &str) -> crate::error::Result<SourceImage> {
+    let is_exr = Path::new(filepath)
+        .extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("exr"));
+    match is_exr {
+        true => read_exr_source(filepath),
+        false => read_image_source(filepath),
+    }
+}
+
+// colour or grey channels and alpha picked by name like `Texture::new` does, other channels
+// such as depth or AOVs don't end up in the texture
+fn read_exr_source(filepath: &str) -> crate::error::Result<SourceImage> {
+    let image = exr::prelude::read_first_flat_layer_from_file(filepath)
+        .map_err(|e| TextureError::exr(filepath, e))?;
+    let layer = image.layer_data;
+    let channels = &layer.channel_data.list;
+    let channel_names: Vec<String> = channels.iter().map(|c| c.name.to_string()).collect();
+    let layout = utils::ChannelLayout::from_names(channel_names.iter().map(String::as_str))
+        .ok_or_else(|| TextureError::Unsupported {
+            path: filepath.to_string(),
+            reason: "no R, G and B or Y channels".to_string(),
+        })?;
+
+    let indices = layout.indices();
+    let mut samples = Vec::with_capacity(layer.size.area() * indices.len());
+    for i in 0..layer.size.area() {
+        for channel in &indices {
+            samples.push(
+                channels[*channel]
+                    .sample_data
+                    .value_by_flat_index(i)
+                    .to_f32(),
+            );
+        }
+    }
+    Ok(SourceImage {
+        size: layer.size,
+        names: layout.names(),
+        samples,
+    })
+}
+
+// 8 and 16 bit sources get normalized into 0.0 - 1.0, float sources keep their values
+fn read_image_source(filepath: &str) -> crate::error::Result<SourceImage> {
+    let img = image::open(Path::new(filepath)).map_err(|e| TextureError::image(filepath, e))?;
+    let size = Vec2(img.width() as usize, img.height() as usize);
+    let (names, samples): (&'static [&'static str], Vec<f32>) =
+        match (img.color().has_color(), img.color().has_alpha()) {
+            (false, false) => (&["Y"], img.to_luma32f().into_raw()),
+            (false, true) => (&["Y", "A"], img.to_luma_alpha32f().into_raw()),
+            (true, false) => (&["R", "G", "B"], img.into_rgb32f().into_raw()),
+            (true, true) => (&["R", "G", "B", "A"], img.into_rgba32f().into_raw()),
+        };
+    Ok(SourceImage {
+        size,
+        names,
+        samples,
+    })
+}
+
+// converts the source into linear values and resamples it to every mip level
+pub(crate) fn mip_levels(
+    source: SourceImage,
+    source_cs: &utils::ColorSpace,
+    options: &TxOptions,
+) -> Vec<MipLevel> {
+    let SourceImage {
+        size: original_size,
+        names,
+        mut samples,
+    } = source;
+    let stride = names.len();
+    let threads = options.thread_count();
+    // alpha is coverage, not colour, it is never converted
+    let has_alpha = names.last() == Some(&"A");
+    let colour = stride - usize::from(has_alpha);
+    // filtered in linear light, averaging encoded values would darken every level,
+    // and premultiplied, so the colour of transparent texels doesn't bleed into opaque ones
+    let srgb = *source_cs == utils::ColorSpace::Srgb;
+    if srgb || has_alpha {
+        let row_len = original_size.x() * stride;
+        resample::for_row_runs(&mut samples, row_len, threads, |_, rows| {
+            for pixel in rows.chunks_mut(stride) {
+                let (colour, alpha) = pixel.split_at_mut(colour);
+                for value in colour.iter_mut() {
+                    if srgb {
+                        *value = srgb_to_linear(*value);
+                    }
+                    if let Some(alpha) = alpha.first() {
+                        *value *= alpha;
+                    }
+                }
+            }
+        });
+    }
+
+    let full_size = options.resize.level_size(original_size);
+    let samples = top_level(samples, original_size, stride, full_size, options, threads);
+    // levels in the order exr stores them, mip levels count along y so they chain like the first rip column
+    let level_sizes: Vec<(Vec2<usize>, Vec2<usize>)> = match options.ripmaps {
+        true => exr::meta::rip_map_levels(LEVEL_ROUNDING, full_size).collect(),
+        false => exr::meta::mip_map_levels(LEVEL_ROUNDING, full_size)
+            .map(|(level, size)| (Vec2(0, level), size))
+            .collect(),
+    };
+
+    let mut levels = Vec::with_capacity(level_sizes.len());
+    // first level of the latest row and the level left of the current one, kept to resample from
+    let mut row_start: Option<(Vec2<usize>, Cow<[f32]>)> = None;
+    let mut left: Option<(Vec2<usize>, Cow<[f32]>)> = None;
+    for (level, level_size) in level_sizes {
+        let previous = match (options.mip_from_previous, level.x()) {
+            (false, _) => None,
+            (true, 0) => row_start.as_ref(),
+            (true, _) => left.as_ref().or(row_start.as_ref()),
+        };
+        // only the top level is ever as large as the samples it comes from and borrows them,
+        // levels below always get made from a larger one
+        let resized = match previous {
+            Some((size, previous)) => Cow::Owned(
+                resample::resize(
+                    previous,
+                    *size,
+                    stride,
+                    level_size,
+                    options.mip_filter,
+                    threads,
+                )
+                .into_owned(),
+            ),
+            None => resample::resize(
+                &samples,
+                full_size,
+                stride,
+                level_size,
+                options.mip_filter,
+                threads,
+            ),
+        };
+
+        // channels picked out of the interleaved samples one at a time, rows spread over the threads,
+        // colour stored straight again, fully transparent texels have none left and come out black
+        let channels: Vec<(&'static str, Vec<f32>)> = names
+            .iter()
+            .enumerate()
+            .map(|(index, name)| {
+                let unpremultiply = has_alpha && index < colour;
+                let mut channel = vec![0.0; level_size.area()];
+                resample::for_row_runs(&mut channel, level_size.x(), threads, |first_y, rows| {
+                    let pixels = resized[first_y * level_size.x() * stride..].chunks(stride);
+                    for (value, pixel) in rows.iter_mut().zip(pixels) {
+                        *value = match (unpremultiply, pixel[stride - 1]) {
+                            (true, alpha) if alpha > 0.0 => pixel[index] / alpha,
+                            (true, _) => 0.0,
+                            (false, _) => pixel[index],
+                        };
+                    }
+                });
+                (*name, channel)
+            })
+            .collect();
+        levels.push(MipLevel {
+            size: level_size,
+            channels,
+        });
+        if options.mip_from_previous {
+            if level.x() == 0 {
+                row_start = Some((level_size, resized));
+                left = None;
+            } else {
+                left = Some((level_size, resized));
+            }
+        }
+    }
+    levels
+}
+
+// samples resized or padded to the top level, taking the source so it is freed as soon as
+// the top level exists, sources already at the size are passed through without a copy
+fn top_level(
+    samples: Vec<f32>,
+    size: Vec2<usize>,
+    stride: usize,
+    full_size: Vec2<usize>,
+    options: &TxOptions,
+    threads: usize,
+) -> Vec<f32> {
+    if size == full_size {
+        return samples;
+    }
+    match options.resize {
+        ResizePolicy::Pad => pad(&samples, size, stride, full_size),
+        _ => resample::resize(
+            &samples,
+            size,
+            stride,
+            full_size,
+            options.mip_filter,
+            threads,
+        )
+        .into_owned(),
+    }
+}
+
+// places the samples in the top left corner and repeats the edge texels into the rest,
+// so filtering across the edge of the source doesn't pull in black
+fn pad(samples: &[f32], size: Vec2<usize>, stride: usize, padded_size: Vec2<usize>) -> Vec<f32> {
+    let mut padded = Vec::with_capacity(padded_size.area() * stride);
+    for y in 0..padded_size.y() {
+        let row = &samples[y.min(size.y() - 1) * size.x() * stride..][..size.x() * stride];
+        padded.extend_from_slice(row);
+        let last = &row[(size.x() - 1) * stride..];
+        for _ in size.x()..padded_size.x() {
+            padded.extend_from_slice(last);
+        }
+    }
+    padded
 }
 
 fn srgb_to_linear(c_srgb: f32) -> f32 {
@@ -1,164 +1,153 @@
 extern crate half;
 extern crate rand;
 
-use exr::block::reader::ChunksReader;
+use exr::block::chunk::Chunk;
+use exr::block::UncompressedBlock;
+use exr::io::PeekRead;
 use exr::math::Vec2;
+use exr::meta::attribute::{AttributeValue, LevelMode, Text, TileDescription};
+use exr::meta::header::Header;
+use exr::meta::{BlockDescription, MetaData};
 use std::fs::{metadata, File};
-use std::io::BufReader;
+use std::io::{BufReader, Read, Seek, SeekFrom};
 use std::path::Path;
 
+use crate::error::TextureError;
+
 // exr imports
 extern crate exr;
 
-/// Collects the average pixel value for each channel.
-/// Does not load the whole image into memory at once: only processes the image block by block.
-/// On my machine, this program analyzes a 3GB file while only allocating 1.1MB.
-pub fn load_tile_data(file_path: &str, mip_map_lvl: usize, tile_index: Vec2<usize>) -> Vec<f32> {
-    use exr::prelude::*;
-
-    let file = BufReader::new(File::open(file_path).expect("failed to read texture"));
-
-    let start_time = ::std::time::Instant::now();
+// where the chunks of the first layer start in the file, in the order of `blocks_increasing_y_order`,
+// the reader is left right after the offset table
+pub fn read_meta_data_and_offsets(
+    file: &mut BufReader<File>,
+) -> exr::error::Result<(MetaData, Vec<u64>)> {
+    // the header ends with a null byte which gets consumed, so nothing stays peeked past it
+    let meta_data = MetaData::read_from_buffered(&mut *file, false)?;
+    MetaData::validate(&meta_data.headers, false)?;
+    let mut offset_tables =
+        MetaData::read_offset_tables(&mut PeekRead::new(&mut *file), &meta_data.headers)?;
+    Ok((meta_data, offset_tables.swap_remove(0)))
+}
 
-    // -- read the file, summing up the average pixel values --
+/// Loads a single tile by seeking straight to its chunk, converted into R,G,B,A,R,G,B,A... values.
+pub fn load_tile_data(
+    file: &mut BufReader<File>,
+    meta_data: &MetaData,
+    layout: &ChannelLayout,
+    chunk_offset: u64,
+) -> exr::error::Result<Vec<f32>> {
+    file.seek(SeekFrom::Start(chunk_offset))?;
+    let chunk = Chunk::read(file, meta_data)?;
+    let block = UncompressedBlock::decompress_chunk(chunk, meta_data, false)?;
+    Ok(block_to_rgba(
+        &block,
+        &meta_data.headers[block.index.layer],
+        layout,
+    ))
+}
 
-    // start reading the file, extracting the meta data of the image
-    let reader = exr::block::read(file, true).unwrap();
+// collects the channel lines of a block and reshuffles them into R,G,B,A,R,G,B,A... form
+fn block_to_rgba(block: &UncompressedBlock, header: &Header, layout: &ChannelLayout) -> Vec<f32> {
+    use exr::prelude::*;
 
-    // print progress only if it advances more than 1%
-    let mut current_progress_percentage = 0;
+    // read and store lines, where each read line holds data for one channel
+    let mut channels: Vec<Vec<f32>> = vec![Vec::new(); header.channels.list.len()];
+    for line in block.lines(&header.channels) {
+        let channel_i = line.location.channel;
+        // channels which don't end up in the texture aren't worth decoding
+        if !layout.uses(channel_i) {
+            continue;
+        }
+        let channel = &header.channels.list[channel_i];
+
+        let mut line_values: Vec<f32> = match channel.sample_type {
+            SampleType::F16 => line
+                .read_samples::<f16>()
+                .map(|v| v.unwrap().to_f32())
+                .collect(),
+
+            SampleType::F32 => line.read_samples::<f32>().map(|v| v.unwrap()).collect(),
+
+            SampleType::U32 => line
+                .read_samples::<u32>()
+                .map(|v| v.unwrap() as f32)
+                .collect(),
+        };
+        channels[channel_i].append(&mut line_values);
+    }
+    layout.interleave(&channels)
+}
 
-    let mut rgba_values: Vec<f32> = Vec::new();
+/// Which channels of a file make up the colour and alpha of a texture, picked by name.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChannelLayout {
+    /// grey from Y, with alpha from A when there is one
+    Grey { y: usize, a: Option<usize> },
+    /// colour from R, G and B, with alpha from A when there is one
+    Rgb {
+        r: usize,
+        g: usize,
+        b: usize,
+        a: Option<usize>,
+    },
+}
+impl ChannelLayout {
+    // colour channels win over Y, None when there are neither R, G and B nor Y
+    pub fn from_names<'a>(names: impl IntoIterator<Item = &'a str>) -> Option<ChannelLayout> {
+        let names: Vec<&str> = names.into_iter().collect();
+        let find = |name: &str| names.iter().position(|n| *n == name);
+        let a = find("A");
+        match (find("R"), find("G"), find("B"), find("Y")) {
+            (Some(r), Some(g), Some(b), _) => Some(ChannelLayout::Rgb { r, g, b, a }),
+            (_, _, _, Some(y)) => Some(ChannelLayout::Grey { y, a }),
+            _ => None,
+        }
+    }
 
-    // create a reader that loads only relevant chunks from the file, and also prints something on progress
-    let reader = reader
-        // filter out only the mip map level and tile we are interested in loading
-        .filter_chunks(true, |meta_data, tile, block| {
-            let header = &meta_data.headers[block.layer];
-            //println!("lvl:{:?} tile:{:?}",block.level, tile.tile_index);
-            tile.tile_index == tile_index && tile.level_index == Vec2(mip_map_lvl, mip_map_lvl)
-        })
-        .unwrap();
-
-    // read all pixel blocks from the image, decompressing in parallel
-    reader
-        .decompress_parallel(true, |meta_data, block| {
-            let header = &meta_data.headers[block.index.layer];
-            //println!("{:?}", header.channels.list);
-            // collect all pixel values from the pixel block
-            let mut l_c = 0;
-            //println!("{:?}",&header.channels);
-            let n_channels = header.channels.list.len();
-            //println!("number of channels: {:?}", n_channels);
-            // read and store lines, where each read line holds data for one channel
-
-            match n_channels {
-                //TOOD handle better than turning into 4 channels
-                1 => {
-                    let mut y_lines: Vec<f32> = Vec::new();
-                    for line in block.lines(&header.channels) {
-                        let channel_i = line.location.channel;
-                        let channel = &header.channels.list[channel_i];
-
-                        let mut line_values: Vec<f32> = match channel.sample_type {
-                            SampleType::F16 => line
-                                .read_samples::<f16>()
-                                .map(|v| v.unwrap().to_f32())
-                                .collect(),
-
-                            SampleType::F32 => {
-                                line.read_samples::<f32>().map(|v| v.unwrap()).collect()
-                            }
-
-                            SampleType::U32 => line
-                                .read_samples::<f32>()
-                                .map(|v| v.unwrap() as f32)
-                                .collect(),
-                        };
-                        y_lines.append(&mut line_values);
-                    }
-                    for i in 0..y_lines.len() {
-                        rgba_values.push(y_lines[i]);
-                        rgba_values.push(y_lines[i]);
-                        rgba_values.push(y_lines[i]);
-                        rgba_values.push(y_lines[i]); // TODO handle alpah better, add option for just rgb
-                    }
-                }
-                //TOOD handle better than turning into 4 channels
-                3 => {
-                    let mut bgr_lines: [Vec<f32>; 3] = [Vec::new(), Vec::new(), Vec::new()];
-                    for line in block.lines(&header.channels) {
-                        let channel_i = line.location.channel;
-                        let channel = &header.channels.list[channel_i];
-
-                        let mut line_values: Vec<f32> = match channel.sample_type {
-                            SampleType::F16 => line
-                                .read_samples::<f16>()
-                                .map(|v| v.unwrap().to_f32())
-                                .collect(),
-
-                            SampleType::F32 => {
-                                line.read_samples::<f32>().map(|v| v.unwrap()).collect()
-                            }
-
-                            SampleType::U32 => line
-                                .read_samples::<f32>()
-                                .map(|v| v.unwrap() as f32)
-                                .collect(),
-                        };
-                        bgr_lines[channel_i].append(&mut line_values);
-                    }
-                    for i in 0..bgr_lines[0].len() {
-                        rgba_values.push(bgr_lines[2][i]);
-                        rgba_values.push(bgr_lines[1][i]);
-                        rgba_values.push(bgr_lines[0][i]);
-                        rgba_values.push(bgr_lines[0][i]); // TODO handle alpah better, add option for just rgb
-                    }
-                }
-                4 => {
-                    let mut abgr_lines: [Vec<f32>; 4] =
-                        [Vec::new(), Vec::new(), Vec::new(), Vec::new()];
-                    for line in block.lines(&header.channels) {
-                        let channel_i = line.location.channel;
-                        let channel = &header.channels.list[channel_i];
-
-                        let mut line_values: Vec<f32> = match channel.sample_type {
-                            SampleType::F16 => line
-                                .read_samples::<f16>()
-                                .map(|v| v.unwrap().to_f32())
-                                .collect(),
-
-                            SampleType::F32 => {
-                                line.read_samples::<f32>().map(|v| v.unwrap()).collect()
-                            }
-
-                            SampleType::U32 => line
-                                .read_samples::<f32>()
-                                .map(|v| v.unwrap() as f32)
-                                .collect(),
-                        };
-                        abgr_lines[channel_i].append(&mut line_values);
-                        //println!("channel: {:?}", channel);
-                        // reshuffle into new vector in a R,G,B,A,R,G,B,A,R,G,B.... form
-                    }
-                    for i in 0..abgr_lines[0].len() {
-                        rgba_values.push(abgr_lines[3][i]);
-                        rgba_values.push(abgr_lines[2][i]);
-                        rgba_values.push(abgr_lines[1][i]);
-                        rgba_values.push(abgr_lines[0][i]);
-                    }
-                }
+    // names of the channels maketx writes for the layout
+    pub(crate) fn names(&self) -> &'static [&'static str] {
+        match *self {
+            ChannelLayout::Grey { a: None, .. } => &["Y"],
+            ChannelLayout::Grey { a: Some(_), .. } => &["Y", "A"],
+            ChannelLayout::Rgb { a: None, .. } => &["R", "G", "B"],
+            ChannelLayout::Rgb { a: Some(_), .. } => &["R", "G", "B", "A"],
+        }
+    }
 
-                _ => {}
-            };
+    // channels the layout reads, in the order of `names`
+    pub(crate) fn indices(&self) -> Vec<usize> {
+        match *self {
+            ChannelLayout::Grey { y, a } => [y].into_iter().chain(a).collect(),
+            ChannelLayout::Rgb { r, g, b, a } => [r, g, b].into_iter().chain(a).collect(),
+        }
+    }
 
-            //println!("lines: {:?}, n of values: {:?}", l_c, values.len());
-            Ok(())
-        })
-        .unwrap();
-    //println!("values: {:?}", values.len());
+    fn uses(&self, channel: usize) -> bool {
+        match *self {
+            ChannelLayout::Grey { y, a } => channel == y || a == Some(channel),
+            ChannelLayout::Rgb { r, g, b, a } => {
+                channel == r || channel == g || channel == b || a == Some(channel)
+            }
+        }
+    }
 
-    rgba_values
+    // reshuffles the planar channels, indexed like the names the layout was made from,
+    // into R,G,B,A,R,G,B,A... form, textures without alpha are opaque
+    pub(crate) fn interleave(&self, channels: &[Vec<f32>]) -> Vec<f32> {
+        let alpha = |a: Option<usize>, i: usize| a.map_or(1.0, |a| channels[a][i]);
+        match *self {
+            ChannelLayout::Grey { y, a } => (0..channels[y].len())
+                .flat_map(|i| {
+                    let y = channels[y][i];
+                    [y, y, y, alpha(a, i)]
+                })
+                .collect(),
+            ChannelLayout::Rgb { r, g, b, a } => (0..channels[r].len())
+                .flat_map(|i| [channels[r][i], channels[g][i], channels[b][i], alpha(a, i)])
+                .collect(),
+        }
+    }
 }
 
 pub fn parent_folder(filepath: String) -> String {
@@ -178,39 +167,136 @@ pub fn tags_to_pattern(filepath: String) -> String {
     // Create a regex pattern that matches anything between '<' and '>'
     let re = Regex::new(r"<[^>]*>").unwrap();
     // Replace anything between '<' and '>' with the replacement text
-    let result = re.replace_all(&filepath, format!("{}", replacement));
+    let result = re.replace_all(&filepath, replacement);
     result.to_string()
 }
 
-use glob::{glob, Pattern};
+use glob::glob;
 
-pub fn list_files_by_pattern(pattern: String) -> Vec<String> {
+pub fn list_files_by_pattern(pattern: String) -> Result<Vec<String>, TextureError> {
     let mut out: Vec<String> = Vec::new();
+    let entries = glob(pattern.as_str()).map_err(|source| TextureError::Pattern {
+        pattern: pattern.clone(),
+        source,
+    })?;
     // Iterate over the files that match the pattern
-    for entry in glob(pattern.as_str().as_ref()).expect("Failed to read glob pattern") {
+    for entry in entries {
         match entry {
             Ok(path) => {
                 if path.is_file() {
-                    out.push(path.to_str().unwrap().to_string());
+                    out.push(path.to_string_lossy().to_string());
                 }
             }
             Err(e) => println!("Error: {}", e), // Handle errors, like invalid paths
         }
     }
-    out
+    Ok(out)
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum ColorSpace {
     Srgb,
     Raw,
 }
 
-pub fn is_file_newer(file1: String, file2: String) -> bool {
-    let metadata1 = metadata(Path::new(&file1)).expect("could not get file metadata");
-    let metadata2 = metadata(Path::new(&file2)).expect("could not get file metadata");
+pub fn is_file_newer(file1: String, file2: String) -> Result<bool, TextureError> {
+    let modified = |file: &String| {
+        metadata(Path::new(file))
+            .and_then(|m| m.modified())
+            .map_err(|e| TextureError::io(file, e))
+    };
+    Ok(modified(&file1)? > modified(&file2)?)
+}
+
+// stable 64 bit FNV-1a hash, the same on every machine and build so it can name shared files
+pub fn fnv1a_64(bytes: &[u8]) -> u64 {
+    fnv1a_64_continue(0xcbf2_9ce4_8422_2325, bytes)
+}
+
+fn fnv1a_64_continue(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+// fnv1a_64 of the file content, read in chunks so big sources don't have to fit in memory
+pub fn file_hash(file_path: &str) -> Result<u64, TextureError> {
+    let mut file = File::open(file_path).map_err(|e| TextureError::io(file_path, e))?;
+    let mut buffer = vec![0u8; 1 << 16];
+    let mut hash = fnv1a_64(&[]);
+    loop {
+        let n = file
+            .read(&mut buffer)
+            .map_err(|e| TextureError::io(file_path, e))?;
+        if n == 0 {
+            return Ok(hash);
+        }
+        hash = fnv1a_64_continue(hash, &buffer[..n]);
+    }
+}
+
+// .tx files and tiled exrs with levels are read as they are, scan line and single level exrs
+// as well as other image formats need converting by maketx first
+pub fn is_texture_file(file_path: &str) -> Result<bool, TextureError> {
+    if file_path.ends_with(".tx") {
+        return Ok(true);
+    }
+    let is_exr = Path::new(file_path)
+        .extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("exr"));
+    if !is_exr {
+        return Ok(false);
+    }
+    let metadata =
+        MetaData::read_from_file(file_path, false).map_err(|e| TextureError::exr(file_path, e))?;
+    Ok(matches!(
+        metadata.headers[0].blocks,
+        BlockDescription::Tiles(TileDescription {
+            level_mode: LevelMode::MipMap | LevelMode::RipMap,
+            ..
+        })
+    ))
+}
 
-    let modified1 = metadata1.modified().expect("could not get file metadata");
-    let modified2 = metadata2.modified().expect("could not get file metadata");
+// wrap modes from the "wrapmodes" attribute of .tx files, e.g. "periodic,clamp", defaults to clamp
+pub fn header_wrap_modes(header: &exr::meta::header::Header) -> Vec2<crate::Wrap> {
+    let clamp = Vec2(crate::Wrap::Clamp, crate::Wrap::Clamp);
+    let Some(wrap_mode_name) = &header.own_attributes.wrap_mode_name else {
+        return clamp;
+    };
+    let wrap_mode_name = wrap_mode_name.to_string();
+    let mut modes = wrap_mode_name.split(',').map(crate::Wrap::from_name);
+    match (modes.next().flatten(), modes.next()) {
+        // a single mode applies to both axes
+        (Some(s), None) => Vec2(s, s),
+        (Some(s), Some(Some(t))) => Vec2(s, t),
+        _ => clamp,
+    }
+}
 
-    modified1 > modified2
+// size of the source a .tx was converted from and the part of the top level it covers,
+// files without the attributes cover the whole level
+pub fn header_original_size(header: &exr::meta::header::Header) -> (Vec2<usize>, Vec2<f32>) {
+    let size = header.layer_size;
+    let original_size = match header
+        .own_attributes
+        .other
+        .get(&Text::from(crate::txmake::ORIGINAL_SIZE_ATTRIBUTE))
+    {
+        Some(AttributeValue::IntVec2(original)) if original.x() > 0 && original.y() > 0 => {
+            Vec2(original.x() as usize, original.y() as usize)
+        }
+        _ => return (size, Vec2(1.0, 1.0)),
+    };
+    let resize = match header
+        .own_attributes
+        .other
+        .get(&Text::from(crate::txmake::RESIZE_ATTRIBUTE))
+    {
+        Some(AttributeValue::Text(name)) => {
+            crate::ResizePolicy::from_name(&name.to_string()).unwrap_or_default()
+        }
+        _ => crate::ResizePolicy::Keep,
+    };
+    (original_size, resize.uv_scale(original_size, size))
 }
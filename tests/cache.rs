@@ -0,0 +1,422 @@
+mod common;
+
+use common::{rgb8, save_gradient, ScratchDir};
+use tundra_textures::error::TextureError;
+use tundra_textures::exr::math::{RoundingMode, Vec2};
+use tundra_textures::exr::meta::{compute_level_count, compute_level_size};
+use tundra_textures::exr::prelude::*;
+use tundra_textures::{
+    utils, Filter, SampleFormat, SampleOptions, TextureCache, TextureHandle, TxOptions, Wrap,
+};
+
+const SIZE: u32 = 256;
+const TILE: usize = 16;
+
+// gradient written as a .tx of small full float tiles, so lookups spread over many tiles
+fn add_gradient(cache: &mut TextureCache, scratch: &ScratchDir) -> TextureHandle {
+    let path = save_gradient(scratch, "gradient.png", SIZE, SIZE);
+    cache.set_tx_options(TxOptions {
+        tile_size: TILE,
+        sample_format: SampleFormat::F32,
+        ..TxOptions::default()
+    });
+    cache
+        .add(
+            path.to_string_lossy().to_string(),
+            &utils::ColorSpace::Raw,
+            true,
+        )
+        .unwrap()
+        .remove(0)
+}
+
+fn texel_uv(x: u32, y: u32) -> Vec2<f32> {
+    Vec2(
+        (x as f32 + 0.5) / SIZE as f32,
+        (y as f32 + 0.5) / SIZE as f32,
+    )
+}
+
+fn assert_texel(value: glam::Vec4, x: u32, y: u32) {
+    let expected = rgb8(x, y).map(|c| c as f32 / 255.0);
+    for c in 0..3 {
+        assert!(
+            (value[c] - expected[c]).abs() < 1e-6,
+            "texel {x},{y} channel {c}: got {}, expected {}",
+            value[c],
+            expected[c]
+        );
+    }
+}
+
+#[test]
+fn eviction_keeps_budget_and_values() {
+    // room for about twenty of the 256 full float tiles of level 0
+    let budget = 20 * TILE * TILE * 4 * 4;
+    let mut cache = TextureCache::with_memory_budget(budget);
+    let scratch = ScratchDir::new("cache_evict");
+    let handle = add_gradient(&mut cache, &scratch);
+    let nearest = SampleOptions::level(0, Filter::Nearest);
+
+    // one texel of every tile, then the same texels again after they got evicted
+    for _ in 0..2 {
+        for tile_y in 0..SIZE / TILE as u32 {
+            for tile_x in 0..SIZE / TILE as u32 {
+                let (x, y) = (tile_x * TILE as u32 + 3, tile_y * TILE as u32 + 5);
+                let value = cache.texture(&handle, texel_uv(x, y), &nearest);
+                assert_texel(value, x, y);
+                let stats = cache.stats();
+                assert!(
+                    stats.bytes_resident <= budget,
+                    "{} bytes resident over the budget of {budget}",
+                    stats.bytes_resident
+                );
+            }
+        }
+    }
+
+    let stats = cache.stats();
+    assert_eq!(stats.tiles_loaded, 2 * 256);
+    assert_eq!(
+        stats.tiles_evicted,
+        stats.tiles_loaded - stats.tiles_resident as u64
+    );
+    assert!(stats.tiles_resident <= 20);
+    assert!(cache.failed_textures().is_empty());
+}
+
+#[test]
+fn eviction_spares_tiles_in_use() {
+    let budget = 20 * TILE * TILE * 4 * 4;
+    let mut cache = TextureCache::with_memory_budget(budget);
+    let scratch = ScratchDir::new("cache_evict_hot");
+    let handle = add_gradient(&mut cache, &scratch);
+    let nearest = SampleOptions::level(0, Filter::Nearest);
+
+    // a tile sampled between every load stays resident while the others cycle through
+    let hot = (5, 7);
+    for tile in 1..256 {
+        let (x, y) = ((tile % 16) * TILE as u32, (tile / 16) * TILE as u32);
+        assert_texel(cache.texture(&handle, texel_uv(x, y), &nearest), x, y);
+        assert_texel(
+            cache.texture(&handle, texel_uv(hot.0, hot.1), &nearest),
+            hot.0,
+            hot.1,
+        );
+    }
+    let stats = cache.stats();
+    // the hot tile was loaded once, every other tile once as well
+    assert_eq!(stats.tiles_loaded, 256);
+    assert!(stats.tiles_evicted > 0);
+}
+
+// bilinear blend of the gradient computed from the 8 bit values, x and y in texel space
+#[test]
+fn tile_loads_reuse_the_open_file() {
+    let mut cache = TextureCache::empty();
+    let scratch = ScratchDir::new("cache_files");
+    let handle = add_gradient(&mut cache, &scratch);
+    let nearest = SampleOptions::level(0, Filter::Nearest);
+    // the header read when adding leaves the file open for the tiles
+    assert_eq!(cache.stats().files_opened, 1);
+
+    // a texel of every tile of level 0
+    for y in (0..SIZE).step_by(TILE) {
+        for x in (0..SIZE).step_by(TILE) {
+            assert_texel(cache.texture(&handle, texel_uv(x, y), &nearest), x, y);
+        }
+    }
+    let stats = cache.stats();
+    assert_eq!(stats.tiles_loaded, (SIZE as usize / TILE).pow(2) as u64);
+    assert_eq!(stats.files_opened, 1);
+    assert_eq!(stats.files_open, 1);
+
+    // without idle handles every load opens the file again
+    cache.set_max_open_files(0);
+    assert_eq!(cache.stats().files_open, 0);
+    let level_1 = SampleOptions::level(1, Filter::Nearest);
+    for x in (0..SIZE).step_by(TILE * 2) {
+        cache.texture(&handle, texel_uv(x, 0), &level_1);
+    }
+    let stats = cache.stats();
+    let loads = stats.tiles_loaded - (SIZE as usize / TILE).pow(2) as u64;
+    assert_eq!(loads, (SIZE as usize / TILE / 2) as u64);
+    assert_eq!(stats.files_opened, 1 + loads);
+    assert_eq!(stats.files_open, 0);
+}
+
+fn bilinear_expected(x: f32, y: f32) -> [f32; 3] {
+    let (x, y) = (x - 0.5, y - 0.5);
+    let (x0, y0) = (x.floor(), y.floor());
+    let (fx, fy) = (x - x0, y - y0);
+    let (x0, y0) = (x0 as u32, y0 as u32);
+    let mut expected = [0.0; 3];
+    for (tx, ty, weight) in [
+        (x0, y0, (1.0 - fx) * (1.0 - fy)),
+        (x0 + 1, y0, fx * (1.0 - fy)),
+        (x0, y0 + 1, (1.0 - fx) * fy),
+        (x0 + 1, y0 + 1, fx * fy),
+    ] {
+        for (e, c) in expected.iter_mut().zip(rgb8(tx, ty)) {
+            *e += c as f32 / 255.0 * weight;
+        }
+    }
+    expected
+}
+
+#[test]
+fn bilinear_across_tile_borders() {
+    let mut cache = TextureCache::empty();
+    let scratch = ScratchDir::new("cache_bilinear");
+    let handle = add_gradient(&mut cache, &scratch);
+    let bilinear = SampleOptions::level(0, Filter::Bilinear);
+    let wrap = Vec2(Wrap::Default, Wrap::Default);
+
+    // texel space positions and how many tiles their four texels fall into
+    for (x, y, tiles) in [
+        (16.25, 15.75, 4),
+        (32.0, 40.5, 2),
+        (100.3, 112.0, 2),
+        (200.6, 130.2, 1),
+    ] {
+        let uv = Vec2(x / SIZE as f32, y / SIZE as f32);
+        let missing = handle
+            .texture()
+            .missing_tiles(uv, 0, Filter::Bilinear, wrap);
+        assert_eq!(missing.len(), tiles, "tiles under {x},{y}");
+
+        let value = cache.texture(&handle, uv, &bilinear);
+        let expected = bilinear_expected(x, y);
+        for c in 0..3 {
+            assert!(
+                (value[c] - expected[c]).abs() < 1e-5,
+                "{x},{y} channel {c}: got {}, expected {}",
+                value[c],
+                expected[c]
+            );
+        }
+        assert!(handle
+            .texture()
+            .missing_tiles(uv, 0, Filter::Bilinear, wrap)
+            .is_empty());
+    }
+}
+
+#[test]
+fn concurrent_misses_decode_once() {
+    let mut cache = TextureCache::empty();
+    let scratch = ScratchDir::new("cache_concurrent");
+    let handle = add_gradient(&mut cache, &scratch);
+    let nearest = SampleOptions::level(0, Filter::Nearest);
+    let threads = 32;
+    let start = std::sync::Barrier::new(threads);
+
+    // every thread misses the same tile at the same time, each at its own texel of it
+    std::thread::scope(|scope| {
+        for i in 0..threads as u32 {
+            let (cache, handle, start) = (&cache, &handle, &start);
+            scope.spawn(move || {
+                let (x, y) = (40 + i % TILE as u32 / 2, 70 + i / 4);
+                start.wait();
+                assert_texel(cache.texture(handle, texel_uv(x, y), &nearest), x, y);
+            });
+        }
+    });
+
+    let stats = cache.stats();
+    assert_eq!(stats.tiles_loaded, 1);
+    assert_eq!(stats.tiles_resident, 1);
+    assert!(cache.failed_textures().is_empty());
+}
+
+// tiled mip mapped exr with every channel constant, read as it is without converting
+fn write_constant_exr(scratch: &ScratchDir, name: &str, channels: &[(&str, f32)]) -> String {
+    let path = scratch.path(name);
+    let size = Vec2(64, 64);
+    let rounding_mode = RoundingMode::Down;
+    let channels = channels
+        .iter()
+        .map(|(name, value)| {
+            let level_data = (0..compute_level_count(rounding_mode, size.x()))
+                .map(|level| {
+                    let side = compute_level_size(rounding_mode, size.x(), level);
+                    FlatSamples::F32(vec![*value; side * side])
+                })
+                .collect();
+            AnyChannel::new(
+                *name,
+                Levels::Mip {
+                    rounding_mode,
+                    level_data,
+                },
+            )
+        })
+        .collect();
+    let encoding = Encoding {
+        compression: Compression::Uncompressed,
+        blocks: Blocks::Tiles(Vec2(16, 16)),
+        line_order: LineOrder::Increasing,
+    };
+    let layer = Layer::new(
+        size,
+        LayerAttributes::named("main"),
+        encoding,
+        AnyChannels::sort(channels),
+    );
+    Image::from_layer(layer).write().to_file(&path).unwrap();
+    path.to_string_lossy().to_string()
+}
+
+#[test]
+fn channels_picked_by_name() {
+    let scratch = ScratchDir::new("cache_channels");
+    let mut cache = TextureCache::empty();
+    cache.set_missing_texture_fallback(false);
+
+    // depth after the colour channels doesn't shift them
+    let path = write_constant_exr(
+        &scratch,
+        "rgbaz.exr",
+        &[("R", 0.25), ("G", 0.5), ("B", 0.75), ("A", 0.6), ("Z", 9.0)],
+    );
+    let handle = cache
+        .add(path, &utils::ColorSpace::Raw, false)
+        .unwrap()
+        .remove(0);
+    for level in [0, 3] {
+        let value = cache.texture(
+            &handle,
+            Vec2(0.3, 0.7),
+            &SampleOptions::level(level, Filter::Bilinear),
+        );
+        assert_eq!(value.to_array(), [0.25, 0.5, 0.75, 0.6], "level {level}");
+    }
+
+    // grey with alpha
+    let path = write_constant_exr(&scratch, "ya.exr", &[("A", 0.5), ("Y", 0.2)]);
+    let handle = cache
+        .add(path, &utils::ColorSpace::Raw, false)
+        .unwrap()
+        .remove(0);
+    let value = cache.texture(&handle, Vec2(0.5, 0.5), &SampleOptions::default());
+    assert_eq!(value.to_array(), [0.2, 0.2, 0.2, 0.5]);
+
+    // neither colour nor grey, there is nothing to sample
+    for (name, channels) in [
+        ("uv.exr", &[("U", 0.1), ("V", 0.2)][..]),
+        ("xyz.exr", &[("X", 0.1), ("Y.x", 0.2), ("Z", 0.3)][..]),
+    ] {
+        let path = write_constant_exr(&scratch, name, channels);
+        assert!(
+            matches!(
+                cache.add(path, &utils::ColorSpace::Raw, false),
+                Err(TextureError::Unsupported { .. })
+            ),
+            "{name}"
+        );
+    }
+    assert!(cache.failed_textures().is_empty());
+}
+
+#[test]
+fn handles_found_by_the_added_path() {
+    let scratch = ScratchDir::new("cache_handles");
+    let on_disk = save_gradient(&scratch, "on_disk.png", 32, 32)
+        .to_string_lossy()
+        .to_string();
+    let in_memory = save_gradient(&scratch, "in_memory.png", 32, 32)
+        .to_string_lossy()
+        .to_string();
+    let missing = scratch.path("missing.png").to_string_lossy().to_string();
+
+    let mut cache = TextureCache::empty();
+    let add = |cache: &mut TextureCache, path: &str| {
+        cache
+            .add(path.to_string(), &utils::ColorSpace::Raw, false)
+            .unwrap()
+            .remove(0)
+    };
+    let on_disk_handle = add(&mut cache, &on_disk);
+    let placeholder = add(&mut cache, &missing);
+    cache.set_tx_options(TxOptions {
+        conversion: tundra_textures::Conversion::InMemory,
+        ..TxOptions::default()
+    });
+    let in_memory_handle = add(&mut cache, &in_memory);
+    let added = [on_disk_handle, placeholder, in_memory_handle];
+
+    // the .tx next to the source is where the texture reads from, not what it is found by
+    assert_ne!(added[0].texture().path, on_disk);
+    for (path, handle) in [on_disk, missing, in_memory].iter().zip(&added) {
+        let found = cache.handle(path).unwrap();
+        assert!(std::sync::Arc::ptr_eq(found.texture(), handle.texture()));
+    }
+}
+
+#[test]
+fn udim_without_tiles_fails() {
+    let scratch = ScratchDir::new("cache_udim");
+    let pattern = scratch
+        .path("missing.<UDIM>.png")
+        .to_string_lossy()
+        .to_string();
+
+    let mut cache = TextureCache::empty();
+    let handles = cache
+        .add(pattern.clone(), &utils::ColorSpace::Raw, false)
+        .unwrap();
+    assert_eq!(handles.len(), 1);
+    let value = cache.texture(&handles[0], Vec2(0.5, 0.5), &SampleOptions::default());
+    assert_eq!(value, cache.error_color());
+    let failed = cache.failed_textures();
+    assert_eq!(failed.len(), 1);
+    assert_eq!(failed[0].0, pattern);
+    assert!(cache.handle(&pattern).is_some());
+
+    cache.set_missing_texture_fallback(false);
+    assert!(matches!(
+        cache.add(pattern, &utils::ColorSpace::Raw, false),
+        Err(TextureError::NoMatches { .. })
+    ));
+}
+
+#[test]
+fn udim_tiles_convert_in_order() {
+    let scratch = ScratchDir::new("cache_udim_tiles");
+    let tiles = [1001, 1002, 1011, 1012];
+    for (i, tile) in tiles.iter().enumerate() {
+        image::RgbImage::from_pixel(8, 8, image::Rgb([i as u8 * 50, 0, 0]))
+            .save(scratch.path(&format!("tile.{tile}.png")))
+            .unwrap();
+    }
+    let pattern = scratch
+        .path("tile.<UDIM>.png")
+        .to_string_lossy()
+        .to_string();
+
+    // one conversion at a time, as many as there are threads, and 0 which still makes progress
+    for max_concurrent_conversions in [1, 0, 8] {
+        let mut cache = TextureCache::empty();
+        cache.set_missing_texture_fallback(false);
+        cache.set_tx_options(TxOptions {
+            conversion: tundra_textures::Conversion::InMemory,
+            sample_format: SampleFormat::F32,
+            threads: 4,
+            max_concurrent_conversions,
+            ..TxOptions::default()
+        });
+        let handles = cache
+            .add(pattern.clone(), &utils::ColorSpace::Raw, true)
+            .unwrap();
+        assert_eq!(handles.len(), tiles.len());
+        for (handle, tile) in handles.iter().zip(tiles) {
+            let tile_path = scratch.path(&format!("tile.{tile}.png"));
+            let found = cache.handle(&tile_path.to_string_lossy()).unwrap();
+            assert!(std::sync::Arc::ptr_eq(found.texture(), handle.texture()));
+        }
+        for (i, handle) in handles.iter().enumerate() {
+            let value = cache.texture(handle, Vec2(0.5, 0.5), &SampleOptions::default());
+            assert!((value.x - (i * 50) as f32 / 255.0).abs() < 1e-6);
+        }
+    }
+}
@@ -0,0 +1,62 @@
+// helpers shared by the integration tests, each test file uses only some of them
+#![allow(dead_code)]
+
+use std::path::{Path, PathBuf};
+use tundra_textures::{utils, TextureCache, TextureHandle};
+
+/// Directory of a single test under the system temp dir, removed with everything in it when dropped.
+pub struct ScratchDir(PathBuf);
+impl ScratchDir {
+    // named after the test and the process, so parallel tests and runs don't share files
+    pub fn new(name: &str) -> Self {
+        let dir =
+            std::env::temp_dir().join(format!("tundra_textures_{}_{}", name, std::process::id()));
+        // left over by a run that didn't get to clean up
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        ScratchDir(dir)
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.0
+    }
+
+    pub fn path(&self, file: &str) -> PathBuf {
+        self.0.join(file)
+    }
+}
+impl Drop for ScratchDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+// colour of the gradient test images, distinct in every channel up to 256 texels
+pub fn rgb8(x: u32, y: u32) -> [u8; 3] {
+    [x as u8, y as u8, ((x + y) / 2) as u8]
+}
+
+// writes the rgb8 gradient as an 8 bit png, returns its path
+pub fn save_gradient(scratch: &ScratchDir, name: &str, width: u32, height: u32) -> PathBuf {
+    let path = scratch.path(name);
+    image::RgbImage::from_fn(width, height, |x, y| image::Rgb(rgb8(x, y)))
+        .save(&path)
+        .unwrap();
+    path
+}
+
+pub fn srgb_to_linear(c: f32) -> f32 {
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+// adds a single file with the cache's tx options, converting it even when its .tx is up to date
+pub fn add(cache: &mut TextureCache, path: &Path, source_cs: utils::ColorSpace) -> TextureHandle {
+    cache
+        .add(path.to_string_lossy().to_string(), &source_cs, true)
+        .unwrap()
+        .remove(0)
+}
@@ -0,0 +1,612 @@
+mod common;
+
+use common::{add, rgb8, save_gradient, srgb_to_linear, ScratchDir};
+use std::path::Path;
+use tundra_textures::exr::math::Vec2;
+use tundra_textures::{
+    utils, Conversion, Filter, MipFilter, ResizePolicy, SampleFormat, SampleOptions, TextureCache,
+    TextureError, TextureHandle, TxOptions,
+};
+
+const SIZE: u32 = 100;
+
+// value at the center of the texel, nearest lookup of the full resolution level
+fn texel(handle: &TextureHandle, x: u32, y: u32) -> glam::Vec4 {
+    level_texel(handle, 0, x as usize, y as usize)
+}
+
+fn level_texel(handle: &TextureHandle, level: usize, x: usize, y: usize) -> glam::Vec4 {
+    let texture = handle.texture();
+    let resolution = texture.mipmaps[level].resolution;
+    let uv = Vec2(
+        (x as f32 + 0.5) / resolution.x() as f32,
+        (y as f32 + 0.5) / resolution.y() as f32,
+    );
+    let (_, tile_pos, tile_index) = texture.tile_loaded(uv, level);
+    texture.sample(uv, level, tile_pos, tile_index).unwrap()
+}
+
+fn assert_close(actual: f32, expected: f32, x: u32, y: u32) {
+    assert!(
+        (actual - expected).abs() < 1e-5,
+        "texel {x},{y}: got {actual}, expected {expected}"
+    );
+}
+
+#[test]
+fn raw_8bit_png_keeps_values() {
+    let scratch = ScratchDir::new("maketx_raw8");
+    let path = save_gradient(&scratch, "raw8.png", SIZE, SIZE);
+
+    let mut cache = TextureCache::empty();
+    let handle = add(&mut cache, &path, utils::ColorSpace::Raw);
+    for (x, y) in [(0, 0), (10, 90), (50, 50), (99, 99)] {
+        let value = texel(&handle, x, y);
+        let expected = rgb8(x, y);
+        assert_close(value.x, expected[0] as f32 / 255.0, x, y);
+        assert_close(value.y, expected[1] as f32 / 255.0, x, y);
+        assert_close(value.z, expected[2] as f32 / 255.0, x, y);
+    }
+}
+
+#[test]
+fn srgb_8bit_png_is_linearized() {
+    let scratch = ScratchDir::new("maketx_srgb8");
+    let path = save_gradient(&scratch, "srgb8.png", SIZE, SIZE);
+
+    let mut cache = TextureCache::empty();
+    // sRGB sources default to half floats, full floats to compare exactly
+    cache.set_tx_options(TxOptions {
+        sample_format: SampleFormat::F32,
+        ..TxOptions::default()
+    });
+    let handle = add(&mut cache, &path, utils::ColorSpace::Srgb);
+    for (x, y) in [(0, 0), (10, 90), (50, 50), (99, 99)] {
+        let value = texel(&handle, x, y);
+        let expected = rgb8(x, y);
+        assert_close(value.x, srgb_to_linear(expected[0] as f32 / 255.0), x, y);
+        assert_close(value.y, srgb_to_linear(expected[1] as f32 / 255.0), x, y);
+        assert_close(value.z, srgb_to_linear(expected[2] as f32 / 255.0), x, y);
+    }
+}
+
+#[test]
+fn raw_16bit_png_keeps_values() {
+    let scratch = ScratchDir::new("maketx_raw16");
+    let path = scratch.path("raw16.png");
+    let rgb16 = |x: u32, y: u32| [(x * 65535 / SIZE) as u16, (y * 65535 / SIZE) as u16, 0];
+    image::ImageBuffer::<image::Rgb<u16>, _>::from_fn(SIZE, SIZE, |x, y| image::Rgb(rgb16(x, y)))
+        .save(&path)
+        .unwrap();
+
+    let mut cache = TextureCache::empty();
+    let handle = add(&mut cache, &path, utils::ColorSpace::Raw);
+    for (x, y) in [(0, 0), (10, 90), (50, 50), (99, 99)] {
+        let value = texel(&handle, x, y);
+        let expected = rgb16(x, y);
+        assert_close(value.x, expected[0] as f32 / 65535.0, x, y);
+        assert_close(value.y, expected[1] as f32 / 65535.0, x, y);
+        assert_close(value.z, 0.0, x, y);
+    }
+}
+
+#[test]
+fn srgb_mips_keep_average_brightness() {
+    // black and white stripes average to 0.5 in linear light, averaging the encoded values
+    // first would end up at the linear value of sRGB 0.5, around 0.21
+    let scratch = ScratchDir::new("maketx_stripes");
+    let path = scratch.path("stripes.png");
+    image::RgbImage::from_fn(SIZE, 60, |x, _| {
+        image::Rgb([if x % 2 == 0 { 255 } else { 0 }; 3])
+    })
+    .save(&path)
+    .unwrap();
+
+    let mut cache = TextureCache::empty();
+    let handle = add(&mut cache, &path, utils::ColorSpace::Srgb);
+    let texture = handle.texture();
+
+    let resolution = texture.mipmaps[0].resolution;
+    let mut sum = glam::Vec4::ZERO;
+    for y in 0..resolution.y() {
+        for x in 0..resolution.x() {
+            sum += level_texel(&handle, 0, x, y);
+        }
+    }
+    let mean = sum / (resolution.x() * resolution.y()) as f32;
+
+    let last = texture.mipmaps.len() - 1;
+    assert_eq!(texture.mipmaps[last].resolution, Vec2(1, 1));
+    let average = level_texel(&handle, last, 0, 0);
+    for c in 0..3 {
+        // stored as half floats by default
+        assert!(
+            (average[c] - mean[c]).abs() < 1e-3,
+            "channel {c}: 1x1 level {}, level 0 mean {}",
+            average[c],
+            mean[c]
+        );
+    }
+}
+
+#[test]
+fn zero_tile_size_is_an_error() {
+    let scratch = ScratchDir::new("maketx_tile0");
+    let path = save_gradient(&scratch, "tile0.png", 8, 8);
+
+    let mut cache = TextureCache::empty();
+    cache.set_missing_texture_fallback(false);
+    for conversion in [Conversion::OnDisk, Conversion::InMemory] {
+        cache.set_tx_options(TxOptions {
+            tile_size: 0,
+            conversion,
+            ..TxOptions::default()
+        });
+        let result = cache.add(
+            path.to_string_lossy().to_string(),
+            &utils::ColorSpace::Raw,
+            true,
+        );
+        assert!(
+            matches!(result, Err(TextureError::InvalidOptions { .. })),
+            "{conversion:?}"
+        );
+    }
+}
+
+#[test]
+fn tx_next_to_source_is_reused_and_not_rewritten_while_read() {
+    let scratch = ScratchDir::new("maketx_settings");
+    let path = save_gradient(&scratch, "settings.png", SIZE, SIZE);
+    let source = path.to_string_lossy().to_string();
+    let options = TxOptions {
+        sample_format: SampleFormat::F32,
+        ..TxOptions::default()
+    };
+
+    let mut cache = TextureCache::empty();
+    cache.set_tx_options(options.clone());
+    let srgb = add(&mut cache, &path, utils::ColorSpace::Srgb);
+    assert_eq!(
+        srgb.texture().path,
+        scratch.path("settings.tx").to_string_lossy()
+    );
+    let (x, y) = (10, 90);
+    let expected = rgb8(x, y);
+    assert_close(
+        texel(&srgb, x, y).y,
+        srgb_to_linear(expected[1] as f32 / 255.0),
+        x,
+        y,
+    );
+
+    // other settings need the .tx converted again, which would change it under the texture reading it
+    cache.set_missing_texture_fallback(false);
+    let Err(TextureError::Unsupported { path: tx, .. }) =
+        cache.add(source.clone(), &utils::ColorSpace::Raw, false)
+    else {
+        panic!("rewrote a .tx the cache still reads");
+    };
+    assert_eq!(tx, srgb.texture().path);
+    assert_close(
+        texel(&srgb, x, y).y,
+        srgb_to_linear(expected[1] as f32 / 255.0),
+        x,
+        y,
+    );
+
+    // a cache of its own picks up the existing .tx as long as the settings match
+    let modified = || {
+        std::fs::metadata(scratch.path("settings.tx"))
+            .unwrap()
+            .modified()
+            .unwrap()
+    };
+    let before = modified();
+    let mut other = TextureCache::empty();
+    other.set_tx_options(options);
+    let again = other
+        .add(source.clone(), &utils::ColorSpace::Srgb, false)
+        .unwrap()
+        .remove(0);
+    assert_eq!(modified(), before);
+    assert_close(texel(&again, x, y).y, texel(&srgb, x, y).y, x, y);
+
+    // and converts it again for other settings once nothing reads it
+    let mut raw_cache = TextureCache::empty();
+    raw_cache.set_tx_options(TxOptions {
+        sample_format: SampleFormat::F32,
+        ..TxOptions::default()
+    });
+    drop((cache, other));
+    let raw = raw_cache
+        .add(source, &utils::ColorSpace::Raw, false)
+        .unwrap()
+        .remove(0);
+    assert_close(texel(&raw, x, y).y, expected[1] as f32 / 255.0, x, y);
+}
+
+#[test]
+fn cache_dir_holds_the_tx_and_skips_up_to_date_ones() {
+    let scratch = ScratchDir::new("maketx_cache_dir");
+    let path = save_gradient(&scratch, "cached.png", SIZE, SIZE);
+    let cache_dir = scratch.path("cache");
+    let options = TxOptions {
+        cache_dir: Some(cache_dir.to_string_lossy().to_string()),
+        ..TxOptions::default()
+    };
+    let convert = |force: bool| {
+        let mut cache = TextureCache::empty();
+        cache.set_missing_texture_fallback(false);
+        cache.set_tx_options(options.clone());
+        let handle = cache
+            .add(
+                path.to_string_lossy().to_string(),
+                &utils::ColorSpace::Raw,
+                force,
+            )
+            .unwrap()
+            .remove(0);
+        let tx = handle.texture().path.clone();
+        let (x, y) = (30, 70);
+        assert_close(texel(&handle, x, y).y, rgb8(x, y)[1] as f32 / 255.0, x, y);
+        tx
+    };
+
+    let tx = convert(true);
+    let tx_file = Path::new(&tx);
+    assert_eq!(tx_file.parent().unwrap(), cache_dir);
+    assert!(tx_file
+        .file_name()
+        .unwrap()
+        .to_string_lossy()
+        .starts_with("cached_"));
+    assert!(!scratch.path("cached.tx").exists());
+    // only the .tx, nothing left of writing it
+    assert_eq!(std::fs::read_dir(&cache_dir).unwrap().count(), 1);
+
+    let modified = || std::fs::metadata(&tx).unwrap().modified().unwrap();
+    let before = modified();
+    assert_eq!(convert(false), tx);
+    assert_eq!(modified(), before);
+}
+
+#[test]
+fn threads_dont_change_levels() {
+    let scratch = ScratchDir::new("maketx_threads");
+    let path = scratch.path("threads.png");
+    image::RgbaImage::from_fn(SIZE, 60, |x, y| {
+        let [r, g, b] = rgb8(x, y);
+        image::Rgba([r, g, b ^ (x * 7 + y * 13) as u8, (x * y) as u8])
+    })
+    .save(&path)
+    .unwrap();
+
+    let convert = |threads: usize| {
+        let mut cache = TextureCache::empty();
+        cache.set_tx_options(TxOptions {
+            conversion: Conversion::InMemory,
+            sample_format: SampleFormat::F32,
+            ripmaps: true,
+            threads,
+            ..TxOptions::default()
+        });
+        add(&mut cache, &path, utils::ColorSpace::Srgb)
+    };
+    let single = convert(1);
+    let many = convert(5);
+
+    let levels = single.texture().mipmaps.len();
+    assert_eq!(levels, many.texture().mipmaps.len());
+    for level in 0..levels {
+        let resolution = single.texture().mipmaps[level].resolution;
+        assert_eq!(resolution, many.texture().mipmaps[level].resolution);
+        for y in 0..resolution.y() {
+            for x in 0..resolution.x() {
+                assert_eq!(
+                    level_texel(&single, level, x, y),
+                    level_texel(&many, level, x, y),
+                    "level {level} texel {x},{y}"
+                );
+            }
+        }
+    }
+}
+
+// alpha of the 8 bit test images, independent of the colour so mixups show
+fn alpha8(x: u32, y: u32) -> u8 {
+    ((x * 3 + y * 5) % 256) as u8
+}
+
+fn add_srgb_f32(path: &Path) -> TextureHandle {
+    let mut cache = TextureCache::empty();
+    cache.set_tx_options(TxOptions {
+        sample_format: SampleFormat::F32,
+        ..TxOptions::default()
+    });
+    add(&mut cache, path, utils::ColorSpace::Srgb)
+}
+
+#[test]
+fn srgb_grey_png_is_opaque() {
+    let scratch = ScratchDir::new("maketx_srgb_y");
+    let path = scratch.path("srgb_y.png");
+    image::GrayImage::from_fn(SIZE, SIZE, |x, y| image::Luma([rgb8(x, y)[0]]))
+        .save(&path)
+        .unwrap();
+
+    let handle = add_srgb_f32(&path);
+    for (x, y) in [(0, 0), (10, 90), (50, 50), (99, 99)] {
+        let value = texel(&handle, x, y);
+        let grey = srgb_to_linear(rgb8(x, y)[0] as f32 / 255.0);
+        for c in 0..3 {
+            assert_close(value[c], grey, x, y);
+        }
+        assert_close(value.w, 1.0, x, y);
+    }
+}
+
+#[test]
+fn srgb_grey_alpha_png_keeps_alpha() {
+    let scratch = ScratchDir::new("maketx_srgb_ya");
+    let path = scratch.path("srgb_ya.png");
+    image::GrayAlphaImage::from_fn(SIZE, SIZE, |x, y| {
+        image::LumaA([rgb8(x, y)[0], alpha8(x, y)])
+    })
+    .save(&path)
+    .unwrap();
+
+    let handle = add_srgb_f32(&path);
+    for (x, y) in [(0, 0), (10, 90), (50, 50), (99, 99)] {
+        let value = texel(&handle, x, y);
+        let grey = srgb_to_linear(rgb8(x, y)[0] as f32 / 255.0);
+        for c in 0..3 {
+            assert_close(value[c], grey, x, y);
+        }
+        assert_close(value.w, alpha8(x, y) as f32 / 255.0, x, y);
+    }
+}
+
+#[test]
+fn srgb_rgba_png_keeps_alpha() {
+    let scratch = ScratchDir::new("maketx_srgb_rgba");
+    let path = scratch.path("srgb_rgba.png");
+    image::RgbaImage::from_fn(SIZE, SIZE, |x, y| {
+        let [r, g, b] = rgb8(x, y);
+        image::Rgba([r, g, b, alpha8(x, y)])
+    })
+    .save(&path)
+    .unwrap();
+
+    let handle = add_srgb_f32(&path);
+    for (x, y) in [(0, 0), (10, 90), (50, 50), (99, 99)] {
+        let value = texel(&handle, x, y);
+        let expected = rgb8(x, y);
+        for c in 0..3 {
+            assert_close(value[c], srgb_to_linear(expected[c] as f32 / 255.0), x, y);
+        }
+        assert_close(value.w, alpha8(x, y) as f32 / 255.0, x, y);
+    }
+}
+
+#[test]
+fn box_filter_halves_into_2x2_averages() {
+    let scratch = ScratchDir::new("maketx_box");
+    let path = scratch.path("box.png");
+    // not a gradient, a filter reaching past the 2x2 block would change the average
+    let value = |x: u32, y: u32| ((x * 37 + y * 91 + x * y) % 256) as u8;
+    image::GrayImage::from_fn(64, 64, |x, y| image::Luma([value(x, y)]))
+        .save(&path)
+        .unwrap();
+
+    let mut cache = TextureCache::empty();
+    cache.set_tx_options(TxOptions {
+        conversion: Conversion::InMemory,
+        mip_filter: MipFilter::Box,
+        sample_format: SampleFormat::F32,
+        ..TxOptions::default()
+    });
+    let handle = add(&mut cache, &path, utils::ColorSpace::Raw);
+    assert_eq!(handle.texture().mipmaps[1].resolution, Vec2(32, 32));
+    for y in 0..32 {
+        for x in 0..32 {
+            let block = [(0, 0), (1, 0), (0, 1), (1, 1)]
+                .map(|(dx, dy)| value(x * 2 + dx, y * 2 + dy) as f32 / 255.0);
+            let expected = block.iter().sum::<f32>() / 4.0;
+            let actual = level_texel(&handle, 1, x as usize, y as usize).x;
+            assert!(
+                (actual - expected).abs() < 1e-6,
+                "texel {x},{y}: got {actual}, expected {expected}"
+            );
+        }
+    }
+}
+
+#[test]
+fn levels_from_previous_have_the_same_sizes() {
+    let scratch = ScratchDir::new("maketx_from_previous");
+    let path = save_gradient(&scratch, "previous.png", SIZE, 60);
+
+    for ripmaps in [false, true] {
+        let resolutions = |mip_from_previous: bool| {
+            let mut cache = TextureCache::empty();
+            cache.set_tx_options(TxOptions {
+                conversion: Conversion::InMemory,
+                ripmaps,
+                mip_from_previous,
+                ..TxOptions::default()
+            });
+            let handle = add(&mut cache, &path, utils::ColorSpace::Raw);
+            let texture = handle.texture();
+            texture
+                .mipmaps
+                .iter()
+                .map(|mipmap| mipmap.resolution)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(resolutions(true), resolutions(false), "ripmaps {ripmaps}");
+    }
+}
+
+#[test]
+fn transparent_colour_doesnt_bleed_into_coarse_levels() {
+    let scratch = ScratchDir::new("maketx_premultiply");
+    let path = scratch.path("checker.png");
+    // opaque red next to transparent green, straight averages would turn every level yellow
+    image::RgbaImage::from_fn(64, 64, |x, y| match (x + y) % 2 {
+        0 => image::Rgba([255, 0, 0, 255]),
+        _ => image::Rgba([0, 255, 0, 0]),
+    })
+    .save(&path)
+    .unwrap();
+
+    for (conversion, mip_filter) in [
+        (Conversion::OnDisk, MipFilter::Box),
+        (Conversion::InMemory, MipFilter::Box),
+        (Conversion::InMemory, MipFilter::Lanczos3),
+    ] {
+        let mut cache = TextureCache::empty();
+        cache.set_tx_options(TxOptions {
+            conversion,
+            mip_filter,
+            sample_format: SampleFormat::F32,
+            ..TxOptions::default()
+        });
+        let handle = add(&mut cache, &path, utils::ColorSpace::Raw);
+        let texture = handle.texture();
+        for level in 1..texture.mipmaps.len() {
+            let resolution = texture.mipmaps[level].resolution;
+            for (x, y) in [(0, 0), (resolution.x() - 1, resolution.y() / 2)] {
+                let value = level_texel(&handle, level, x, y);
+                let context = format!("{mip_filter:?} level {level} texel {x},{y}: {value}");
+                assert!((value.x - 1.0).abs() < 1e-4, "{context}");
+                assert!(value.y.abs() < 1e-4, "{context}");
+                if mip_filter == MipFilter::Box {
+                    assert!((value.w - 0.5).abs() < 1e-6, "{context}");
+                }
+            }
+        }
+        // nothing to average at the top, opaque texels keep their colour
+        assert_eq!(texel(&handle, 0, 0), glam::Vec4::new(1.0, 0.0, 0.0, 1.0));
+        assert_eq!(texel(&handle, 1, 0).w, 0.0);
+    }
+}
+
+#[test]
+fn padded_uv_maps_onto_source() {
+    let scratch = ScratchDir::new("maketx_pad");
+    let path = save_gradient(&scratch, "pad.png", SIZE, 60);
+
+    for conversion in [Conversion::OnDisk, Conversion::InMemory] {
+        let mut cache = TextureCache::empty();
+        cache.set_tx_options(TxOptions {
+            resize: ResizePolicy::Pad,
+            conversion,
+            sample_format: SampleFormat::F32,
+            ..TxOptions::default()
+        });
+        let handle = add(&mut cache, &path, utils::ColorSpace::Raw);
+        assert_eq!(handle.texture().resolution, (128, 64));
+        assert_eq!(handle.texture().original_resolution, (100, 60));
+
+        // uv spans the source, not the padded levels
+        let nearest = SampleOptions::level(0, Filter::Nearest);
+        for (uv, (x, y)) in [
+            (Vec2(0.995, 0.99), (99, 59)),
+            (Vec2(0.005, 0.01), (0, 0)),
+            (Vec2(0.5, 0.5), (50, 30)),
+        ] {
+            let value = cache.texture(&handle, uv, &nearest);
+            let expected = rgb8(x, y);
+            for c in 0..3 {
+                assert!(
+                    (value[c] - expected[c] as f32 / 255.0).abs() < 1e-5,
+                    "{conversion:?} uv {uv:?} channel {c}: got {}, expected texel {x},{y}",
+                    value[c]
+                );
+            }
+        }
+    }
+}
+
+// value of a channel at texel x, y
+type ChannelFn = fn(usize, usize) -> f32;
+
+// scan line exr with a channel per name
+fn write_scanline_exr(path: &Path, channels: &[(&str, ChannelFn)]) {
+    use tundra_textures::exr::prelude::*;
+    let size = Vec2(SIZE as usize, 60);
+    let channels = channels
+        .iter()
+        .map(|(name, value)| {
+            let samples = (0..size.area())
+                .map(|i| value(i % size.x(), i / size.x()))
+                .collect();
+            AnyChannel::new(*name, FlatSamples::F32(samples))
+        })
+        .collect();
+    let layer = Layer::new(
+        size,
+        LayerAttributes::named("main"),
+        Encoding::FAST_LOSSLESS,
+        AnyChannels::sort(channels),
+    );
+    Image::from_layer(layer).write().to_file(path).unwrap();
+}
+
+#[test]
+fn scanline_exr_sources_convert() {
+    let scratch = ScratchDir::new("maketx_scanline_exr");
+    let rgbaz = scratch.path("rgbaz.exr");
+    write_scanline_exr(
+        &rgbaz,
+        &[
+            ("R", |x, _| x as f32 / 100.0),
+            ("G", |_, y| y as f32 / 60.0),
+            ("B", |x, y| (x * y) as f32 / 6000.0),
+            ("A", |x, _| 1.0 - x as f32 / 200.0),
+            // depth sorts after the colour channels and must not end up in them
+            ("Z", |_, _| 100.0),
+        ],
+    );
+    // a displacement map, grey only
+    let grey = scratch.path("y.exr");
+    write_scanline_exr(&grey, &[("Y", |x, y| (x + 2 * y) as f32 / 4.0)]);
+
+    for conversion in [Conversion::OnDisk, Conversion::InMemory] {
+        let mut cache = TextureCache::empty();
+        cache.set_tx_options(TxOptions {
+            conversion,
+            sample_format: SampleFormat::F32,
+            ..TxOptions::default()
+        });
+        let rgbaz = add(&mut cache, &rgbaz, utils::ColorSpace::Raw);
+        let grey = add(&mut cache, &grey, utils::ColorSpace::Raw);
+        for (x, y) in [(0, 0), (10, 50), (99, 59)] {
+            let (fx, fy) = (x as f32, y as f32);
+            let value = texel(&rgbaz, x, y);
+            let expected = [fx / 100.0, fy / 60.0, fx * fy / 6000.0, 1.0 - fx / 200.0];
+            for c in 0..4 {
+                assert_close(value[c], expected[c], x, y);
+            }
+            let value = texel(&grey, x, y);
+            for c in 0..3 {
+                assert_close(value[c], (fx + 2.0 * fy) / 4.0, x, y);
+            }
+            assert_close(value.w, 1.0, x, y);
+        }
+
+        // nothing to make colour or grey from
+        let uv = scratch.path("uv.exr");
+        write_scanline_exr(&uv, &[("U", |_, _| 0.1), ("V", |_, _| 0.2)]);
+        cache.set_missing_texture_fallback(false);
+        assert!(matches!(
+            cache.add(
+                uv.to_string_lossy().to_string(),
+                &utils::ColorSpace::Raw,
+                true
+            ),
+            Err(TextureError::Unsupported { .. })
+        ));
+    }
+}
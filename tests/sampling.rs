@@ -0,0 +1,310 @@
+mod common;
+
+use common::{save_gradient, ScratchDir};
+use tundra_textures::exr::math::Vec2;
+use tundra_textures::exr::meta::attribute::Text;
+use tundra_textures::exr::meta::header::Header;
+use tundra_textures::{
+    utils, Conversion, Filter, SampleFormat, SampleOptions, TextureCache, TextureHandle, TxOptions,
+    Wrap,
+};
+
+const SIZE: u32 = 512;
+
+// horizontal black and white stripes four texels high, constant along x
+fn stripe(y: u32) -> f32 {
+    if (y / 4).is_multiple_of(2) {
+        1.0
+    } else {
+        0.0
+    }
+}
+
+fn add_stripes(
+    cache: &mut TextureCache,
+    scratch: &ScratchDir,
+    name: &str,
+    tx_options: TxOptions,
+) -> TextureHandle {
+    let path = scratch.path(name);
+    image::GrayImage::from_fn(SIZE, SIZE, |_, y| image::Luma([(stripe(y) * 255.0) as u8]))
+        .save(&path)
+        .unwrap();
+    cache.set_tx_options(TxOptions {
+        conversion: Conversion::InMemory,
+        sample_format: SampleFormat::F32,
+        ..tx_options
+    });
+    cache
+        .add(
+            path.to_string_lossy().to_string(),
+            &utils::ColorSpace::Raw,
+            true,
+        )
+        .unwrap()
+        .remove(0)
+}
+
+#[test]
+fn anisotropic_follows_sheared_footprints() {
+    let scratch = ScratchDir::new("sampling_anisotropic");
+    let mut cache = TextureCache::empty();
+    let handle = add_stripes(&mut cache, &scratch, "stripes.png", TxOptions::default());
+
+    // both derivatives run mostly along the stripes, the footprint is a thin sheared ellipse
+    // less than a texel across them, so the stripes have to stay sharp
+    let options = SampleOptions::anisotropic(Vec2(0.1, 0.0), Vec2(0.1, 0.001), 256.0);
+    for y in (2..SIZE).step_by(4).take(20) {
+        let uv = Vec2(0.5, (y as f32 + 0.5) / SIZE as f32);
+        let value = cache.texture(&handle, uv, &options).x;
+        assert!(
+            (value - stripe(y)).abs() < 0.05,
+            "stripe at {y}: got {value}, expected {}",
+            stripe(y)
+        );
+    }
+
+    // the same footprint turned across the stripes averages them out
+    let options = SampleOptions::anisotropic(Vec2(0.0, 0.1), Vec2(0.001, 0.1), 256.0);
+    let value = cache
+        .texture(&handle, Vec2(0.5, 0.5 + 2.0 / SIZE as f32), &options)
+        .x;
+    assert!((value - 0.5).abs() < 0.1, "across the stripes got {value}");
+}
+
+#[test]
+fn wrap_modes_map_outside_texels() {
+    let size = 4;
+    // texel coordinates from two periods before the texture to two after it
+    let coords: Vec<i64> = (-8..12).collect();
+    let apply = |wrap: Wrap| -> Vec<Option<usize>> {
+        coords.iter().map(|c| wrap.apply(*c, size)).collect()
+    };
+    let some =
+        |texels: &[usize]| -> Vec<Option<usize>> { texels.iter().map(|t| Some(*t)).collect() };
+
+    assert_eq!(
+        apply(Wrap::Periodic),
+        some(&[0, 1, 2, 3, 0, 1, 2, 3, 0, 1, 2, 3, 0, 1, 2, 3, 0, 1, 2, 3])
+    );
+    assert_eq!(
+        apply(Wrap::Clamp),
+        some(&[0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 2, 3, 3, 3, 3, 3, 3, 3, 3, 3])
+    );
+    assert_eq!(
+        apply(Wrap::Mirror),
+        some(&[0, 1, 2, 3, 3, 2, 1, 0, 0, 1, 2, 3, 3, 2, 1, 0, 0, 1, 2, 3])
+    );
+    // clamps when nothing else is asked for
+    assert_eq!(apply(Wrap::Default), apply(Wrap::Clamp));
+    let black: Vec<Option<usize>> = coords
+        .iter()
+        .map(|c| (0..size as i64).contains(c).then_some(*c as usize))
+        .collect();
+    assert_eq!(apply(Wrap::Black), black);
+}
+
+#[test]
+fn wrap_modes_from_tx_header() {
+    let header_with = |wrap_modes: Option<&str>| {
+        let mut header = Header::new(Text::from("main"), Vec2(4, 4), Default::default());
+        header.own_attributes.wrap_mode_name = wrap_modes.map(Text::from);
+        utils::header_wrap_modes(&header)
+    };
+    assert_eq!(
+        header_with(Some("periodic,clamp")),
+        Vec2(Wrap::Periodic, Wrap::Clamp)
+    );
+    // a single mode applies to both axes
+    assert_eq!(
+        header_with(Some("mirror")),
+        Vec2(Wrap::Mirror, Wrap::Mirror)
+    );
+    assert_eq!(header_with(Some("black")), Vec2(Wrap::Black, Wrap::Black));
+    // missing or unknown modes clamp
+    assert_eq!(header_with(None), Vec2(Wrap::Clamp, Wrap::Clamp));
+    assert_eq!(
+        header_with(Some("sideways")),
+        Vec2(Wrap::Clamp, Wrap::Clamp)
+    );
+}
+
+#[test]
+fn lookups_resolve_default_wrap() {
+    let scratch = ScratchDir::new("sampling_default_wrap");
+    let mut cache = TextureCache::empty();
+    let handle = add_stripes(&mut cache, &scratch, "stripes.png", TxOptions::default());
+    let nearest = SampleOptions::level(0, tundra_textures::Filter::Nearest);
+    // texel row 2 one period below the texture, the last row is a black stripe
+    let uv = Vec2(0.5, 1.0 + 2.5 / SIZE as f32);
+    assert_eq!(stripe(SIZE - 1), 0.0);
+
+    let lookup = |wrap: Wrap| {
+        cache
+            .texture(&handle, uv, &nearest.with_wrap(Vec2(wrap, wrap)))
+            .x
+    };
+    assert_eq!(lookup(Wrap::Periodic), stripe(2));
+    assert_eq!(lookup(Wrap::Clamp), stripe(SIZE - 1));
+    assert_eq!(lookup(Wrap::Black), 0.0);
+    // textures converted in memory clamp unless told otherwise
+    assert_eq!(lookup(Wrap::Default), lookup(Wrap::Clamp));
+}
+
+#[test]
+fn ripmaps_keep_thin_footprints_sharp() {
+    let scratch = ScratchDir::new("sampling_ripmaps");
+    let mut cache = TextureCache::empty();
+    let rip = add_stripes(
+        &mut cache,
+        &scratch,
+        "stripes_rip.png",
+        TxOptions {
+            ripmaps: true,
+            // every rip level from the full resolution takes long in debug builds
+            mip_from_previous: true,
+            ..TxOptions::default()
+        },
+    );
+    let mip = add_stripes(
+        &mut cache,
+        &scratch,
+        "stripes_mip.png",
+        TxOptions::default(),
+    );
+
+    // sixteen texels along the stripes, a quarter of a texel across them
+    let options =
+        SampleOptions::trilinear(Vec2(16.0 / SIZE as f32, 0.0), Vec2(0.0, 0.25 / SIZE as f32));
+    for y in (2..SIZE).step_by(4).take(20) {
+        let uv = Vec2(0.5, (y as f32 + 0.5) / SIZE as f32);
+        // the y level stays at full resolution, only x gets the coarse level
+        let value = cache.texture(&rip, uv, &options).x;
+        assert!(
+            (value - stripe(y)).abs() < 1e-4,
+            "stripe at {y}: got {value}, expected {}",
+            stripe(y)
+        );
+    }
+
+    // mip levels shrink both axes by the long side and blur the stripes into grey
+    let value = cache
+        .texture(&mip, Vec2(0.5, 2.5 / SIZE as f32), &options)
+        .x;
+    assert!((value - 0.5).abs() < 0.1, "mip lookup got {value}");
+}
+
+#[test]
+fn footprint_of_2_to_the_k_texels_selects_level_k() {
+    let scratch = ScratchDir::new("sampling_mip_level");
+    let mut cache = TextureCache::empty();
+    let handle = add_stripes(&mut cache, &scratch, "stripes.png", TxOptions::default());
+    let texture = handle.texture();
+    let footprint = |texels: f32| {
+        let duv = texels / SIZE as f32;
+        (Vec2(duv, 0.0), Vec2(0.0, duv))
+    };
+
+    // 512 texels make levels 0 to 9
+    for k in 0..=9 {
+        let (duv_dx, duv_dy) = footprint(2f32.powi(k));
+        assert_eq!(texture.mip_level(duv_dx, duv_dy), k as f32);
+        // whole levels read a single one
+        let uv = Vec2(0.3, 0.7);
+        assert_eq!(
+            cache.texture(&handle, uv, &SampleOptions::trilinear(duv_dx, duv_dy)),
+            cache.texture(
+                &handle,
+                uv,
+                &SampleOptions::level(k as usize, Filter::Bilinear)
+            ),
+            "level {k}"
+        );
+    }
+    // either side of the chain clamps
+    let (duv_dx, duv_dy) = footprint(0.25);
+    assert_eq!(texture.mip_level(duv_dx, duv_dy), 0.0);
+    let (duv_dx, duv_dy) = footprint(4096.0);
+    assert_eq!(texture.mip_level(duv_dx, duv_dy), 9.0);
+}
+
+#[test]
+fn fractional_levels_blend_their_neighbours() {
+    let scratch = ScratchDir::new("sampling_blend");
+    let mut cache = TextureCache::empty();
+    let handle = add_stripes(&mut cache, &scratch, "stripes.png", TxOptions::default());
+
+    // inside a white stripe, level 2 still sees it while level 3 is mostly grey
+    let uv = Vec2(0.5, 1.5 / SIZE as f32);
+    let fine = cache.texture(&handle, uv, &SampleOptions::level(2, Filter::Bilinear));
+    let coarse = cache.texture(&handle, uv, &SampleOptions::level(3, Filter::Bilinear));
+    assert!(
+        (fine.x - coarse.x).abs() > 0.1,
+        "levels {fine} and {coarse} too alike"
+    );
+
+    for blend in [0.25f32, 0.5, 0.75] {
+        let duv = 2f32.powf(2.0 + blend) / SIZE as f32;
+        let (duv_dx, duv_dy) = (Vec2(duv, 0.0), Vec2(0.0, duv));
+        let level = handle.texture().mip_level(duv_dx, duv_dy);
+        assert!((level - (2.0 + blend)).abs() < 1e-5, "got level {level}");
+        let value = cache.texture(&handle, uv, &SampleOptions::trilinear(duv_dx, duv_dy));
+        let expected = fine.lerp(coarse, level.fract());
+        assert!(
+            (value - expected).abs().max_element() < 1e-5,
+            "blend {blend}: got {value}, expected {expected}"
+        );
+    }
+}
+
+#[test]
+fn non_square_ripmap_mip_chain_reaches_one_texel() {
+    let scratch = ScratchDir::new("sampling_rip_chain");
+    let path = save_gradient(&scratch, "gradient.png", 100, 60);
+    let add = |cache: &mut TextureCache, ripmaps: bool| {
+        cache.set_tx_options(TxOptions {
+            conversion: Conversion::OnDisk,
+            sample_format: SampleFormat::F32,
+            cache_dir: Some(
+                scratch
+                    .path(&format!("rip_{ripmaps}"))
+                    .to_string_lossy()
+                    .to_string(),
+            ),
+            ripmaps,
+            mip_from_previous: true,
+            ..TxOptions::default()
+        });
+        cache
+            .add(
+                path.to_string_lossy().to_string(),
+                &utils::ColorSpace::Raw,
+                true,
+            )
+            .unwrap()
+            .remove(0)
+    };
+    let mut cache = TextureCache::empty();
+    let rip = add(&mut cache, true);
+    let mip = add(&mut cache, false);
+
+    // footprints of twice the texture pick the coarsest level, 100x60 rounds up to 8 levels
+    let (whole_x, whole_y) = (Vec2(2.0, 0.0), Vec2(0.0, 2.0));
+    assert_eq!(mip.texture().mip_level(whole_x, whole_y), 7.0);
+    assert_eq!(rip.texture().mip_level(whole_x, whole_y), 7.0);
+    for level in 0..=7 {
+        assert_eq!(
+            rip.texture().mipmaps[level].resolution,
+            mip.texture().mipmaps[level].resolution,
+            "level {level}"
+        );
+        let options = SampleOptions::level(level, Filter::Nearest);
+        let rip_value = cache.texture(&rip, Vec2(0.5, 0.5), &options);
+        let mip_value = cache.texture(&mip, Vec2(0.5, 0.5), &options);
+        assert!(
+            (rip_value - mip_value).abs().max_element() < 0.02,
+            "level {level}: ripmap {rip_value}, mipmap {mip_value}"
+        );
+    }
+    assert_eq!(rip.texture().mipmaps[7].resolution, Vec2(1, 1));
+}